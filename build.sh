@@ -3,4 +3,4 @@
 set -e
 
 cross build --target=arm-unknown-linux-gnueabi
-scp target/arm-unknown-linux-gnueabi/debug/pizero-gadget-gamepads pi@raspberrypi.local:
+scp target/arm-unknown-linux-gnueabi/debug/pizero-gadget-gamepads target/arm-unknown-linux-gnueabi/debug/gadgetctl pi@raspberrypi.local:
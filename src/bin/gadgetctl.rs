@@ -0,0 +1,265 @@
+use anyhow::{anyhow, bail, Context, Result};
+use gilrs::Button;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use pizero_gadget_gamepads::control::{
+    send_request, ControllerId, ControllerInfo, DaemonStatus, Request, Response, SlotStatus,
+    DEFAULT_SOCKET_PATH,
+};
+
+const USAGE: &str = "\
+Usage: gadgetctl [--socket <path>] [--json] <command> [<args>]
+
+Commands:
+  status                           Show the daemon's status and connected controllers
+  slots                            Show what each gadget device is being used for
+  assign <controller> <slot>       Move a controller to a different gadget device
+  detach <slot>                    Stop sending input to a gadget device
+  attach <slot>                    Resume sending input to a detached gadget device
+  profile [<name>]                 Switch mapping profile, or clear it if no name is given
+  press <slot> <button>... [--hold <ms>]
+                                   Press buttons on a gadget device (default: 100ms)
+  reload                           Reload the daemon's config file
+  play <slot> <file>               Play an nx-TAS script or a recording to a gadget device
+  stop <slot>                      Stop playing to a gadget device
+
+Options:
+  --socket <path>  The daemon's control socket (default: /run/pizero-gadget-gamepads.sock)
+  --json           Print the daemon's raw JSON response
+";
+
+struct Args {
+    socket: PathBuf,
+    json: bool,
+    hold_ms: u64,
+    command: Vec<String>,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = Args {
+        socket: PathBuf::from(DEFAULT_SOCKET_PATH),
+        json: false,
+        hold_ms: 100,
+        command: vec![],
+    };
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--socket" => {
+                args.socket = iter
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| anyhow!("--socket needs a value"))?
+            }
+            "--json" => args.json = true,
+            "--hold" => {
+                let ms = iter.next().ok_or_else(|| anyhow!("--hold needs a value"))?;
+                args.hold_ms = ms.parse().with_context(|| format!("Bad --hold: {ms}"))?;
+            }
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                std::process::exit(0);
+            }
+            _ => args.command.push(arg),
+        }
+    }
+    Ok(args)
+}
+
+/// Parse a value the same way the daemon will, e.g. a gilrs button name.
+fn parse_json_str<T: DeserializeOwned>(s: &str, what: &str) -> Result<T> {
+    serde_json::from_value(serde_json::Value::String(s.to_owned()))
+        .map_err(|_| anyhow!("Unknown {what}: {s}"))
+}
+
+fn parse_index(s: &str, what: &str) -> Result<usize> {
+    s.parse().map_err(|_| anyhow!("Bad {what}: {s}"))
+}
+
+fn parse_request(args: &Args) -> Result<Request> {
+    let command = args.command.iter().map(String::as_str).collect::<Vec<_>>();
+    Ok(match command.as_slice() {
+        ["status"] => Request::Status,
+        ["slots"] => Request::ListSlots,
+        ["assign", controller, slot] => Request::Assign {
+            controller: ControllerId(parse_index(controller, "controller")?),
+            slot: parse_index(slot, "slot")?,
+        },
+        ["detach", slot] => Request::Detach {
+            slot: parse_index(slot, "slot")?,
+        },
+        ["attach", slot] => Request::Attach {
+            slot: parse_index(slot, "slot")?,
+        },
+        ["profile"] => Request::Profile { name: None },
+        ["profile", name] => Request::Profile {
+            name: Some((*name).to_owned()),
+        },
+        ["press", slot, buttons @ ..] if !buttons.is_empty() => Request::Press {
+            slot: parse_index(slot, "slot")?,
+            buttons: buttons
+                .iter()
+                .map(|b| parse_json_str::<Button>(b, "button"))
+                .collect::<Result<_>>()?,
+            hold_ms: args.hold_ms,
+        },
+        ["reload"] => Request::Reload,
+        ["play", slot, file] => Request::Play {
+            slot: parse_index(slot, "slot")?,
+            // The daemon probably has a different working directory.
+            file: fs::canonicalize(file).with_context(|| format!("Can't find {file}"))?,
+        },
+        ["stop", slot] => Request::Stop {
+            slot: parse_index(slot, "slot")?,
+        },
+        [] => bail!("No command given\n\n{USAGE}"),
+        _ => bail!("Bad command: {}\n\n{USAGE}", command.join(" ")),
+    })
+}
+
+/// Print `rows` as a table with a header row, padding each column to fit.
+fn print_table<const N: usize>(header: [&str; N], rows: Vec<[String; N]>) {
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let print_row = |cells: [&str; N]| {
+        let line = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, w)| format!("{cell:w$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    print_row(header);
+    for row in &rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+}
+
+fn print_controllers(controllers: &[ControllerInfo]) {
+    print_table(
+        ["ID", "NAME", "SLOTS", "KEY", "MAPPING"],
+        controllers
+            .iter()
+            .map(|c| {
+                let slots = c
+                    .slots
+                    .iter()
+                    .map(|s| format!("{} ({})", s.index, s.path))
+                    .collect::<Vec<_>>();
+                [
+                    c.id.to_string(),
+                    c.name.clone(),
+                    if slots.is_empty() {
+                        "-".to_owned()
+                    } else {
+                        slots.join(", ")
+                    },
+                    c.key.clone(),
+                    c.mapping.clone().unwrap_or_else(|| "-".to_owned()),
+                ]
+            })
+            .collect(),
+    );
+}
+
+fn print_status(status: &DaemonStatus) {
+    println!("Gadget:   {}", status.gadget_path);
+    println!("Slots:    {}", status.slot_count);
+    if let Some(state) = &status.host_state {
+        println!("Host:     {state}");
+    }
+    println!(
+        "Profile:  {}",
+        status.profile.as_deref().unwrap_or("(none)")
+    );
+    println!("Profiles: {}", status.profiles.join(", "));
+    if let Some(interval) = status.report_interval_ms {
+        println!(
+            "Reports:  every {interval}ms ({} overruns)",
+            status.overruns
+        );
+    }
+    println!();
+    print_controllers(&status.controllers);
+}
+
+fn print_slots(slots: &[SlotStatus], controllers: &[ControllerInfo]) {
+    print_table(
+        ["SLOT", "DEVICE", "STATE", "CONTROLLER"],
+        slots
+            .iter()
+            .map(|s| {
+                let state = match (s.detached, s.controller) {
+                    _ if s.playing.is_some() => "playing",
+                    (true, _) => "detached",
+                    (false, Some(_)) => "in use",
+                    (false, None) => "free",
+                };
+                let controller = match s.controller {
+                    Some(id) => {
+                        let name = controllers
+                            .iter()
+                            .find(|c| c.id == id)
+                            .map(|c| c.name.as_str())
+                            .unwrap_or_default();
+                        format!("{id}: {name}")
+                    }
+                    None => "-".to_owned(),
+                };
+                [
+                    s.index.to_string(),
+                    s.path.clone(),
+                    state.to_owned(),
+                    controller,
+                ]
+            })
+            .collect(),
+    );
+}
+
+fn run() -> Result<bool> {
+    let args = parse_args()?;
+    let request = parse_request(&args)?;
+    let response = send_request(&args.socket, &request)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+        return Ok(!matches!(response, Response::Error { .. }));
+    }
+    match response {
+        Response::Ok => {}
+        Response::Error { message } => {
+            eprintln!("Error: {message}");
+            return Ok(false);
+        }
+        Response::Status { daemon } => print_status(&daemon),
+        Response::Controllers { controllers } => print_controllers(&controllers),
+        Response::Slots { slots } => {
+            let controllers = match send_request(&args.socket, &Request::ListControllers)? {
+                Response::Controllers { controllers } => controllers,
+                _ => vec![],
+            };
+            print_slots(&slots, &controllers);
+        }
+    }
+    Ok(true)
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
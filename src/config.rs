@@ -0,0 +1,421 @@
+use anyhow::{bail, Context, Result};
+use gilrs::{Axis, Button};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::control::DEFAULT_SOCKET_PATH;
+use crate::keyboard_mouse::parse_key;
+use crate::raw_mapping::parse_evdev_code;
+
+/// The config file that is used if one isn't specified on the command line.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/pizero-gadget-gamepads.toml";
+
+/// Daemon configuration, loaded from a TOML file.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The number of gadget devices to create.
+    pub gadget_count: usize,
+    /// Where to create the emulated controllers.
+    pub output: Output,
+    /// Send every gadget device its latest report at this interval, in milliseconds,
+    /// instead of only when its input changes.
+    pub report_interval_ms: Option<u64>,
+    /// After sending a report, wait this long in milliseconds before sending another
+    /// one, and only send the latest of any reports in between.
+    pub coalesce_ms: Option<u64>,
+    /// Where to persist which gadget device each known controller was assigned to.
+    pub slot_affinity_file: Option<PathBuf>,
+    /// Record every report sent to the gadget devices to this file.
+    pub record_file: Option<PathBuf>,
+    /// A file of extra SDL controller mappings, one per line.
+    pub mapping_file: Option<PathBuf>,
+    /// A directory of extra SDL controller mapping files, which must be named `*.txt`.
+    pub mapping_dir: Option<PathBuf>,
+    /// Where to listen for control connections. Set to an empty string to disable.
+    pub control_socket: PathBuf,
+    /// The mapping profile to use at startup.
+    pub profile: Option<String>,
+    /// Named mapping profiles, which can be switched between at runtime.
+    pub profiles: HashMap<String, Profile>,
+    /// Physical controllers that should drive more than one gadget device.
+    pub split: Vec<SplitConfig>,
+    /// Controllers without an SDL mapping that should be mapped from their evdev codes.
+    pub raw_mapping: Vec<RawMapping>,
+    /// Devices to read directly from evdev instead of through gilrs.
+    pub evdev: Vec<EvdevConfig>,
+    /// Keyboards and mice that should drive a gadget device like a gamepad.
+    pub keyboard_mouse: Vec<KeyboardMouseConfig>,
+    /// UDP ports to accept controller state from.
+    pub network: Vec<NetworkConfig>,
+    /// DSU (cemuhook) servers to read controllers from.
+    pub dsu: Vec<DsuConfig>,
+    /// Buttons that wake the host when they're pressed while it's asleep. Empty to
+    /// never wake it.
+    pub wake_buttons: Vec<Button>,
+    /// Overrides for how the gadget identifies itself to the host.
+    pub usb: UsbConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            gadget_count: 1,
+            output: Output::Gadget,
+            report_interval_ms: None,
+            coalesce_ms: None,
+            slot_affinity_file: None,
+            record_file: None,
+            mapping_file: None,
+            mapping_dir: None,
+            control_socket: PathBuf::from(DEFAULT_SOCKET_PATH),
+            profile: None,
+            profiles: HashMap::new(),
+            split: vec![],
+            raw_mapping: vec![],
+            evdev: vec![],
+            keyboard_mouse: vec![],
+            network: vec![],
+            dsu: vec![],
+            wake_buttons: vec![Button::Mode],
+            usb: UsbConfig::default(),
+        }
+    }
+}
+
+/// Where the emulated controllers are created.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// USB gadget HID functions, which the console sees.
+    #[default]
+    Gadget,
+    /// uhid devices on this machine, for testing without a gadget-capable board.
+    Uhid,
+}
+
+/// A mapping profile, which remaps buttons and axes for every controller.
+///
+/// Buttons and axes that aren't listed are left as-is.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Map of gadget button => physical controller button.
+    pub buttons: HashMap<Button, Button>,
+    /// Map of gadget axis => physical controller axis.
+    pub axes: HashMap<Axis, Axis>,
+}
+
+/// Split a single physical controller across multiple gadget devices.
+///
+/// This is useful for things like 2-player arcade panels that enumerate as
+/// a single device.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitConfig {
+    /// The name of the controller, as reported by gilrs.
+    pub name: String,
+    /// One entry per gadget device that this controller should drive.
+    pub slots: Vec<SplitSlot>,
+}
+
+/// The subset of a physical controller's inputs that drive one gadget device.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SplitSlot {
+    /// Map of gadget button => physical controller button.
+    pub buttons: HashMap<Button, Button>,
+    /// Map of gadget axis => physical controller axis.
+    pub axes: HashMap<Axis, Axis>,
+}
+
+/// Map a controller that has no SDL mapping using its evdev codes.
+///
+/// An SDL mapping is built from these settings when the controller is connected.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawMapping {
+    /// The name of the controller, as reported by gilrs.
+    pub name: String,
+    /// Guess bindings for any buttons and axes that aren't listed below from
+    /// the evdev codes the controller has.
+    #[serde(default = "default_true")]
+    pub guess: bool,
+    /// Map of gadget button => evdev code name, e.g. `BTN_TRIGGER` or `ABS_HAT0X`.
+    #[serde(default)]
+    pub buttons: HashMap<Button, String>,
+    /// Map of gadget axis => evdev code name, e.g. `ABS_Z`.
+    #[serde(default)]
+    pub axes: HashMap<Axis, String>,
+}
+
+/// A device to read directly from evdev instead of through gilrs.
+///
+/// This works for devices that gilrs doesn't consider to be gamepads, and has
+/// less overhead.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvdevConfig {
+    /// The device node, preferably one of the stable links in `/dev/input/by-id`.
+    pub path: PathBuf,
+    /// Take exclusive access to the device, so that nothing else on the Pi
+    /// (including gilrs) sees its input.
+    #[serde(default = "default_true")]
+    pub grab: bool,
+    /// Guess bindings for any buttons and axes that aren't listed below from
+    /// the evdev codes the device has.
+    #[serde(default = "default_true")]
+    pub guess: bool,
+    /// Map of gadget button => evdev code name, e.g. `BTN_TRIGGER` or `ABS_HAT0X`.
+    #[serde(default)]
+    pub buttons: HashMap<Button, String>,
+    /// Map of gadget axis => evdev code name, e.g. `ABS_Z`.
+    #[serde(default)]
+    pub axes: HashMap<Axis, String>,
+}
+
+/// Drive a gadget device with a keyboard and mouse.
+///
+/// The left stick is driven by keys, the right stick by moving the mouse, and
+/// buttons by keys or mouse buttons.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyboardMouseConfig {
+    /// The name of the controller, for `split` entries and the control socket.
+    #[serde(default = "default_keyboard_mouse_name")]
+    pub name: String,
+    /// The keyboard's device node, preferably one of the stable links in `/dev/input/by-id`.
+    pub keyboard: PathBuf,
+    /// The mouse's device node.
+    pub mouse: Option<PathBuf>,
+    /// Take exclusive access to the keyboard and mouse, so that nothing else on
+    /// the Pi sees their input.
+    #[serde(default = "default_true")]
+    pub grab: bool,
+    /// Map of gadget button => key or mouse button code name, e.g. `KEY_SPACE` or `BTN_LEFT`.
+    ///
+    /// These replace the default bindings for the buttons listed.
+    #[serde(default)]
+    pub buttons: HashMap<Button, String>,
+    /// The keys that push the left stick.
+    #[serde(default)]
+    pub left_stick: StickKeys,
+    /// How far the right stick moves for a given mouse speed.
+    #[serde(default = "default_sensitivity")]
+    pub sensitivity: f32,
+    /// How much faster the right stick moves as the mouse speeds up. 1.0 is linear.
+    #[serde(default = "default_acceleration")]
+    pub acceleration: f32,
+    /// Push the right stick up when the mouse moves down.
+    #[serde(default)]
+    pub invert_y: bool,
+}
+
+/// The keys that push a stick in each direction.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StickKeys {
+    pub up: String,
+    pub down: String,
+    pub left: String,
+    pub right: String,
+}
+
+impl Default for StickKeys {
+    fn default() -> Self {
+        StickKeys {
+            up: "KEY_W".to_owned(),
+            down: "KEY_S".to_owned(),
+            left: "KEY_A".to_owned(),
+            right: "KEY_D".to_owned(),
+        }
+    }
+}
+
+/// Drive a gadget device with state packets sent over UDP, e.g. from another
+/// machine on the LAN.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// The name of the controller, for `split` entries and the control socket.
+    #[serde(default = "default_network_name")]
+    pub name: String,
+    /// The address and port to listen on, e.g. `0.0.0.0:7531`.
+    pub listen: SocketAddr,
+    /// If set, packets must be signed with an HMAC-SHA256 using this secret.
+    pub secret: Option<String>,
+    /// If set, signed packets must have a timestamp in microseconds since the Unix
+    /// epoch that's no more than this many milliseconds from the daemon's clock, so
+    /// old packets can't be replayed after the daemon restarts.
+    pub max_age_ms: Option<u64>,
+    /// How long to hold the last state for when packets stop arriving, in
+    /// milliseconds, before releasing all inputs.
+    #[serde(default = "default_network_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How long after packets stop arriving to give up the gadget device, in milliseconds.
+    #[serde(default = "default_network_disconnect_ms")]
+    pub disconnect_ms: u64,
+}
+
+/// Read a controller from a DSU (cemuhook) server, such as DS4Windows or a phone app.
+/// Only its buttons, sticks and triggers are used.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DsuConfig {
+    /// The name of the controller, for `split` entries and the control socket.
+    #[serde(default = "default_dsu_name")]
+    pub name: String,
+    /// The server's address and port. DSU servers usually use port 26760.
+    pub server: SocketAddr,
+    /// Which of the server's controllers to use, from 0 to 3.
+    #[serde(default)]
+    pub slot: u8,
+}
+
+/// Overrides for how the gadget identifies itself to the host. Anything that isn't
+/// set is the emulated gamepad's own value.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsbConfig {
+    /// The serial number, for every language. Give each gadget its own, so hosts
+    /// can tell them apart.
+    pub serial_number: Option<String>,
+    /// The device release number, e.g. `0x0100` for 1.00.
+    pub bcd_device: Option<u16>,
+    /// The USB version, e.g. `0x0200` for USB 2.0.
+    pub bcd_usb: Option<u16>,
+    /// The most power the gadget draws from the bus, in mA.
+    pub max_power: Option<u16>,
+    /// String tables by language ID in hex, e.g. `0x409` for US English.
+    ///
+    /// There's always a US English table, and strings that a table doesn't have
+    /// are taken from it.
+    pub strings: BTreeMap<String, UsbStrings>,
+}
+
+/// The strings the gadget reports to the host in one language.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsbStrings {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// The name of the gadget's configuration.
+    pub configuration: Option<String>,
+}
+
+/// Parse a USB language ID like `0x409`.
+pub fn parse_language(id: &str) -> Result<u16> {
+    let hex = id.strip_prefix("0x").unwrap_or(id);
+    u16::from_str_radix(hex, 16).with_context(|| format!("Bad USB language ID: {id}"))
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_keyboard_mouse_name() -> String {
+    "Keyboard and mouse".to_owned()
+}
+
+fn default_sensitivity() -> f32 {
+    1.0
+}
+
+fn default_acceleration() -> f32 {
+    1.0
+}
+
+fn default_network_name() -> String {
+    "Network controller".to_owned()
+}
+
+fn default_network_timeout_ms() -> u64 {
+    250
+}
+
+fn default_network_disconnect_ms() -> u64 {
+    5000
+}
+
+fn default_dsu_name() -> String {
+    "DSU controller".to_owned()
+}
+
+impl Config {
+    /// Load the config from `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read config: {path:?}"))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse config: {path:?}"))
+    }
+
+    /// Check the config for errors that parsing can't catch.
+    pub fn check(&self) -> Result<()> {
+        if self.report_interval_ms == Some(0) {
+            bail!("report_interval_ms must be at least 1");
+        }
+        let split_slots = self.split.iter().map(|s| s.slots.len()).sum::<usize>();
+        if split_slots > self.gadget_count {
+            bail!(
+                "split entries need {split_slots} gadget devices, but gadget_count is {}",
+                self.gadget_count
+            );
+        }
+        if let Some(name) = &self.profile {
+            if !self.profiles.contains_key(name) {
+                bail!("Unknown profile in config: {name}");
+            }
+        }
+        for raw in &self.raw_mapping {
+            let codes = raw.buttons.values().chain(raw.axes.values());
+            for code in codes {
+                parse_evdev_code(code)
+                    .with_context(|| format!("Bad raw mapping for {}", raw.name))?;
+            }
+        }
+        for evdev in &self.evdev {
+            let codes = evdev.buttons.values().chain(evdev.axes.values());
+            for code in codes {
+                parse_evdev_code(code)
+                    .with_context(|| format!("Bad evdev mapping for {:?}", evdev.path))?;
+            }
+        }
+        for kbm in &self.keyboard_mouse {
+            let stick = &kbm.left_stick;
+            let keys = [&stick.up, &stick.down, &stick.left, &stick.right];
+            for key in kbm.buttons.values().chain(keys) {
+                parse_key(key).with_context(|| format!("Bad key binding for {}", kbm.name))?;
+            }
+        }
+        let mut languages = HashMap::new();
+        for language in self.usb.strings.keys() {
+            if let Some(other) = languages.insert(parse_language(language)?, language) {
+                bail!("USB strings {other:?} and {language:?} are for the same language");
+            }
+        }
+        Ok(())
+    }
+
+    /// The control socket path, if the control socket is enabled.
+    pub fn control_socket(&self) -> Option<&Path> {
+        if self.control_socket.as_os_str().is_empty() {
+            None
+        } else {
+            Some(&self.control_socket)
+        }
+    }
+
+    /// Find the raw mapping for the controller named `name`, if any.
+    pub fn raw_mapping_for(&self, name: &str) -> Option<&RawMapping> {
+        self.raw_mapping.iter().find(|r| r.name == name)
+    }
+
+    /// Find the split configuration for the controller named `name`, if any.
+    pub fn split_for(&self, name: &str) -> Option<&SplitConfig> {
+        self.split.iter().find(|s| s.name == name)
+    }
+}
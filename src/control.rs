@@ -0,0 +1,340 @@
+use anyhow::{bail, Context, Result};
+use gilrs::Button;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// The control socket that is used if one isn't specified in the config.
+pub const DEFAULT_SOCKET_PATH: &str = "/run/pizero-gadget-gamepads.sock";
+/// The longest request line a client can send before it's disconnected.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// The most response data that can be waiting for a client to read it before the
+/// client is disconnected.
+const MAX_PENDING_OUTPUT: usize = 1024 * 1024;
+
+/// Identifies a connected controller, for as long as it stays connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ControllerId(pub usize);
+
+impl Display for ControllerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A command sent to the daemon over the control socket.
+///
+/// Requests and responses are sent as one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Get an overview of the daemon's state.
+    Status,
+    /// List connected controllers and the gadget devices they're mapped to.
+    ListControllers,
+    /// List the gadget devices and what they're being used for.
+    ListSlots,
+    /// Move a controller to a different gadget device.
+    ///
+    /// If the gadget device is in use by another controller the two are swapped.
+    /// For a split controller this moves its first gadget device.
+    Assign {
+        controller: ControllerId,
+        slot: usize,
+    },
+    /// Stop routing input to a gadget device and don't hand it out to new controllers.
+    Detach { slot: usize },
+    /// Undo a previous `Detach`.
+    Attach { slot: usize },
+    /// Switch to the named mapping profile, or back to no profile.
+    Profile { name: Option<String> },
+    /// Press buttons on a gadget device, and release them after `hold_ms` milliseconds.
+    Press {
+        slot: usize,
+        buttons: Vec<Button>,
+        hold_ms: u64,
+    },
+    /// Reload the config file.
+    Reload,
+    /// Play an nx-TAS script or a recording to a gadget device, in place of the
+    /// controller mapped to it. `file` is read by the daemon.
+    Play { slot: usize, file: PathBuf },
+    /// Stop a playback started with `Play`.
+    Stop { slot: usize },
+}
+
+/// The daemon's reply to a `Request`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Error { message: String },
+    Status { daemon: DaemonStatus },
+    Controllers { controllers: Vec<ControllerInfo> },
+    Slots { slots: Vec<SlotStatus> },
+}
+
+impl From<anyhow::Error> for Response {
+    fn from(e: anyhow::Error) -> Self {
+        Response::Error {
+            message: format!("{e:#}"),
+        }
+    }
+}
+
+/// An overview of the daemon's state.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    /// The configfs path of the gadget.
+    pub gadget_path: String,
+    pub slot_count: usize,
+    /// The mapping profile currently in use, if any.
+    pub profile: Option<String>,
+    /// All available mapping profiles.
+    pub profiles: Vec<String>,
+    /// How often reports are sent, if they're sent at a fixed rate.
+    pub report_interval_ms: Option<u64>,
+    /// How many times reports were sent late enough to miss an interval.
+    pub overruns: u64,
+    /// The state of the USB connection to the host, like "configured" or "suspended",
+    /// if the gadget is a USB gadget and its state could be read.
+    pub host_state: Option<String>,
+    pub controllers: Vec<ControllerInfo>,
+}
+
+/// Information about a connected controller.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControllerInfo {
+    pub id: ControllerId,
+    pub name: String,
+    /// The key used to identify this controller in the slot affinity table.
+    pub key: String,
+    /// Where the SDL mapping in use for this controller came from, if it has one.
+    pub mapping: Option<String>,
+    /// The gadget devices this controller is mapped to. Empty if it isn't mapped.
+    pub slots: Vec<SlotInfo>,
+}
+
+/// Information about a gadget device.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SlotInfo {
+    pub index: usize,
+    pub path: String,
+}
+
+/// What a gadget device is being used for.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SlotStatus {
+    pub index: usize,
+    pub path: String,
+    /// The controller mapped to this gadget device, if any.
+    pub controller: Option<ControllerId>,
+    pub detached: bool,
+    /// The file being played to this gadget device, if any.
+    pub playing: Option<PathBuf>,
+}
+
+struct Client {
+    stream: UnixStream,
+    /// Input that isn't a complete request line yet.
+    buf: Vec<u8>,
+    /// Responses that the client hasn't read yet.
+    out: Vec<u8>,
+    /// Set once the client has stopped sending, so it's only kept until it's been
+    /// sent all of its responses.
+    closed: bool,
+}
+
+/// Listens on the control socket and reads requests from connected clients.
+///
+/// Everything is non-blocking so that this can be polled from the main loop.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl ControlServer {
+    pub fn bind(path: &Path) -> Result<Self> {
+        // Clean up after a previous run that didn't exit cleanly, but only if what's
+        // there is a socket that nothing is listening on.
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if UnixStream::connect(path).is_ok() {
+                    bail!("Control socket {path:?} is in use, is another instance running?");
+                }
+                fs::remove_file(path)
+                    .with_context(|| format!("Failed to remove stale control socket: {path:?}"))?;
+            }
+            Ok(_) => bail!("Control socket path {path:?} exists and isn't a socket"),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to check control socket: {path:?}"))
+            }
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind control socket: {path:?}"))?;
+        listener.set_nonblocking(true)?;
+        info!("Listening for control connections on {path:?}");
+        Ok(ControlServer {
+            listener,
+            path: path.to_owned(),
+            clients: vec![],
+        })
+    }
+
+    /// Accept any pending connections and pass any complete requests to `handler`,
+    /// sending its response back to the client.
+    pub fn poll(&mut self, mut handler: impl FnMut(Request) -> Response) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    debug!("Control client connected");
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        error!("Failed to set control client non-blocking: {e}");
+                        continue;
+                    }
+                    self.clients.push(Client {
+                        stream,
+                        buf: vec![],
+                        out: vec![],
+                        closed: false,
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("Failed to accept control connection: {e}");
+                    break;
+                }
+            }
+        }
+        self.clients
+            .retain_mut(|client| match client.poll(&mut handler) {
+                Ok(open) => open,
+                Err(e) => {
+                    debug!("Dropping control client: {e}");
+                    false
+                }
+            });
+    }
+}
+
+impl Client {
+    /// Handle any complete requests from this client, and send it as much of its
+    /// responses as it will take. Returns `false` once the client has disconnected
+    /// and has nothing left to be sent.
+    fn poll(&mut self, handler: &mut impl FnMut(Request) -> Response) -> io::Result<bool> {
+        let mut chunk = [0; 1024];
+        while !self.closed {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.closed = true,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        while let Some(end) = self.buf.iter().position(|b| *b == b'\n') {
+            let line = self.buf.drain(..=end).collect::<Vec<_>>();
+            let response = match serde_json::from_slice::<Request>(&line) {
+                Ok(request) => {
+                    debug!("Control request: {request:?}");
+                    handler(request)
+                }
+                Err(e) => Response::Error {
+                    message: format!("Bad request: {e}"),
+                },
+            };
+            self.send(&response)?;
+        }
+        if self.buf.len() > MAX_REQUEST_LEN {
+            return Err(io::Error::other("Request is too long"));
+        }
+        self.flush()?;
+        Ok(!self.closed || !self.out.is_empty())
+    }
+
+    /// Queue a response to be sent to the client.
+    fn send(&mut self, response: &Response) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, response)?;
+        self.out.push(b'\n');
+        if self.out.len() > MAX_PENDING_OUTPUT {
+            return Err(io::Error::other("Client isn't reading its responses"));
+        }
+        Ok(())
+    }
+
+    /// Write as much of the queued responses as the client will take without blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match self.stream.write(&self.out) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send a single request to the daemon listening on `path` and wait for its response.
+pub fn send_request(path: &Path, request: &Request) -> Result<Response> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Failed to connect to control socket: {path:?}"))?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    serde_json::from_str(&response).context("Bad response from daemon")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn bind_replaces_only_stale_sockets() {
+        // Something that isn't a socket is left alone.
+        let path = temp_path("control-file");
+        fs::write(&path, "keep me").unwrap();
+        assert!(ControlServer::bind(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+        fs::remove_file(&path).unwrap();
+
+        // A socket that's being listened on means another instance is running.
+        let path = temp_path("control-live");
+        let server = ControlServer::bind(&path).unwrap();
+        assert!(ControlServer::bind(&path).is_err());
+        drop(server);
+
+        // A socket that nothing is listening on is replaced.
+        let path = temp_path("control-stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let server = ControlServer::bind(&path).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,201 @@
+use gilrs::{Axis, Button, Gamepad};
+use std::collections::{HashMap, HashSet};
+
+/// The buttons that gadget devices can have, in gilrs' standard layout.
+pub const BUTTONS: &[Button] = &[
+    Button::South,
+    Button::East,
+    Button::North,
+    Button::West,
+    // Intentionally skipping C,
+    // Intentionally skipping Z,
+    Button::LeftTrigger,
+    Button::LeftTrigger2,
+    Button::RightTrigger,
+    Button::RightTrigger2,
+    Button::Select,
+    Button::Start,
+    Button::Mode,
+    Button::LeftThumb,
+    Button::RightThumb,
+    Button::DPadUp,
+    Button::DPadDown,
+    Button::DPadLeft,
+    Button::DPadRight,
+];
+
+/// The axes that gadget devices can have.
+pub const AXES: &[Axis] = &[
+    Axis::LeftStickX,
+    Axis::LeftStickY,
+    Axis::RightStickX,
+    Axis::RightStickY,
+];
+
+/// The position of an analog stick, from -1.0 to 1.0 on each axis. Up is positive for Y.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stick {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// The directions of a hat switch (d-pad) that are pressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hat {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+/// Motion sensor readings.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Motion {
+    /// Acceleration in g, as (x, y, z).
+    pub accel: [f32; 3],
+    /// Angular velocity in degrees per second, as (pitch, yaw, roll).
+    pub gyro: [f32; 3],
+}
+
+/// A point touching a touchpad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Touch {
+    /// Identifies the touch for as long as it stays down.
+    pub id: u8,
+    /// The position from 0.0 to 1.0, with (0.0, 0.0) at the top left.
+    pub x: f32,
+    pub y: f32,
+}
+
+/// The state of a controller's inputs, independent of where they come from.
+///
+/// Input backends build these and `HIDGamepad` implementations turn them into reports.
+/// Buttons and axes are named with gilrs' standard layout, the same as in the config.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ControllerState {
+    /// The digital buttons that are pressed, apart from the d-pad.
+    pub buttons: HashSet<Button>,
+    pub left_stick: Stick,
+    pub right_stick: Stick,
+    /// How far the left analog trigger is pulled, from 0.0 to 1.0.
+    pub left_trigger: f32,
+    /// How far the right analog trigger is pulled, from 0.0 to 1.0.
+    pub right_trigger: f32,
+    /// The d-pad.
+    pub hat: Hat,
+    /// Motion sensor readings, if the controller has motion sensors.
+    pub motion: Option<Motion>,
+    /// The points touching the controller's touchpad.
+    pub touch: Vec<Touch>,
+}
+
+impl ControllerState {
+    pub fn is_pressed(&self, button: Button) -> bool {
+        match button {
+            Button::DPadUp => self.hat.up,
+            Button::DPadDown => self.hat.down,
+            Button::DPadLeft => self.hat.left,
+            Button::DPadRight => self.hat.right,
+            b => self.buttons.contains(&b),
+        }
+    }
+
+    pub fn set_pressed(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::DPadUp => self.hat.up = pressed,
+            Button::DPadDown => self.hat.down = pressed,
+            Button::DPadLeft => self.hat.left = pressed,
+            Button::DPadRight => self.hat.right = pressed,
+            Button::Unknown => {}
+            b if pressed => {
+                self.buttons.insert(b);
+            }
+            b => {
+                self.buttons.remove(&b);
+            }
+        }
+    }
+
+    /// How far `button` is pressed, from 0.0 to 1.0. Only the triggers are analog.
+    pub fn button_value(&self, button: Button) -> f32 {
+        match button {
+            Button::LeftTrigger2 if self.left_trigger > 0.0 => self.left_trigger,
+            Button::RightTrigger2 if self.right_trigger > 0.0 => self.right_trigger,
+            b if self.is_pressed(b) => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Set how far `button` is pressed, and whether it counts as pressed.
+    fn set_button(&mut self, button: Button, pressed: bool, value: f32) {
+        self.set_pressed(button, pressed);
+        match button {
+            Button::LeftTrigger2 => self.left_trigger = value,
+            Button::RightTrigger2 => self.right_trigger = value,
+            _ => {}
+        }
+    }
+
+    /// Press `buttons`, in addition to anything already pressed.
+    pub fn press(&mut self, buttons: &[Button]) {
+        for button in buttons {
+            self.set_button(*button, true, 1.0);
+        }
+    }
+
+    /// The value of a stick axis from -1.0 to 1.0.
+    pub fn axis(&self, axis: Axis) -> f32 {
+        match axis {
+            Axis::LeftStickX => self.left_stick.x,
+            Axis::LeftStickY => self.left_stick.y,
+            Axis::RightStickX => self.right_stick.x,
+            Axis::RightStickY => self.right_stick.y,
+            _ => 0.0,
+        }
+    }
+
+    pub fn set_axis(&mut self, axis: Axis, value: f32) {
+        match axis {
+            Axis::LeftStickX => self.left_stick.x = value,
+            Axis::LeftStickY => self.left_stick.y = value,
+            Axis::RightStickX => self.right_stick.x = value,
+            Axis::RightStickY => self.right_stick.y = value,
+            _ => {}
+        }
+    }
+
+    /// Build the state seen through maps of new buttons and axes => buttons and axes
+    /// in this state. Anything that isn't in the maps is left at rest.
+    pub fn remapped(
+        &self,
+        buttons: &HashMap<Button, Button>,
+        axes: &HashMap<Axis, Axis>,
+    ) -> ControllerState {
+        let mut state = ControllerState {
+            motion: self.motion,
+            touch: self.touch.clone(),
+            ..Default::default()
+        };
+        for (to, from) in buttons {
+            state.set_button(*to, self.is_pressed(*from), self.button_value(*from));
+        }
+        for (to, from) in axes {
+            state.set_axis(*to, self.axis(*from));
+        }
+        state
+    }
+}
+
+impl From<&Gamepad<'_>> for ControllerState {
+    fn from(gamepad: &Gamepad<'_>) -> Self {
+        let mut state = ControllerState::default();
+        for button in BUTTONS {
+            let value = gamepad.button_data(*button).map_or(0.0, |d| d.value());
+            state.set_button(*button, gamepad.is_pressed(*button), value);
+        }
+        for axis in AXES {
+            state.set_axis(*axis, gamepad.value(*axis));
+        }
+        state
+    }
+}
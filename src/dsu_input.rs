@@ -0,0 +1,356 @@
+use anyhow::{bail, Context, Result};
+use gilrs::Button;
+use log::debug;
+use scroll::{Pread, LE};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::process;
+use std::time::{Duration, Instant};
+
+use crate::config::DsuConfig;
+use crate::controller_state::{ControllerState, Stick};
+use crate::network_input::NetworkEvent;
+
+const CLIENT_MAGIC: &[u8; 4] = b"DSUC";
+const SERVER_MAGIC: &[u8; 4] = b"DSUS";
+const PROTOCOL_VERSION: u16 = 1001;
+/// The length of the header that starts every message.
+const HEADER_LEN: usize = 16;
+/// The message type for controller data, and for requesting it.
+const MSG_DATA: u32 = 0x100002;
+/// The length of a controller data message.
+const DATA_LEN: usize = 100;
+/// The slot state of a connected controller.
+const SLOT_CONNECTED: u8 = 2;
+
+/// How often to ask for data. Servers stop sending after a few seconds without a request.
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+/// How long after data stops arriving to count the controller as disconnected.
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The buttons in the first button byte, by bit.
+const BUTTONS_1: &[(u8, Button)] = &[
+    (0x01, Button::Select),
+    (0x02, Button::LeftThumb),
+    (0x04, Button::RightThumb),
+    (0x08, Button::Start),
+    (0x10, Button::DPadUp),
+    (0x20, Button::DPadRight),
+    (0x40, Button::DPadDown),
+    (0x80, Button::DPadLeft),
+];
+
+/// The buttons in the second button byte, by bit.
+const BUTTONS_2: &[(u8, Button)] = &[
+    (0x01, Button::LeftTrigger2),
+    (0x02, Button::RightTrigger2),
+    (0x04, Button::LeftTrigger),
+    (0x08, Button::RightTrigger),
+    (0x10, Button::North),
+    (0x20, Button::East),
+    (0x40, Button::South),
+    (0x80, Button::West),
+];
+
+/// The CRC-32 that DSU messages are checked with (the same one as zlib).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn stick_axis(value: u8) -> f32 {
+    ((value as f32 - 128.0) / 127.0).clamp(-1.0, 1.0)
+}
+
+/// Parse a controller data message into the slot it's for and the controller's
+/// state, which is `None` if the controller isn't connected.
+///
+/// Only the buttons, sticks and triggers are read. None of the emulated controllers
+/// have a touchpad or motion sensors, so there's nothing to send the rest to.
+fn parse_data(buf: &[u8]) -> Result<(u8, Option<ControllerState>)> {
+    if buf.len() < DATA_LEN {
+        bail!("Message is too short");
+    }
+    if &buf[..4] != SERVER_MAGIC {
+        bail!("Bad magic");
+    }
+    let mut unsigned = buf.to_vec();
+    unsigned[8..12].fill(0);
+    if crc32(&unsigned) != buf.pread_with::<u32>(8, LE)? {
+        bail!("Bad CRC");
+    }
+    if buf.pread_with::<u32>(HEADER_LEN, LE)? != MSG_DATA {
+        bail!("Not a controller data message");
+    }
+    let slot = buf[20];
+    if buf[21] != SLOT_CONNECTED {
+        return Ok((slot, None));
+    }
+
+    let mut state = ControllerState::default();
+    for (bit, button) in BUTTONS_1 {
+        state.set_pressed(*button, buf[36] & bit != 0);
+    }
+    for (bit, button) in BUTTONS_2 {
+        state.set_pressed(*button, buf[37] & bit != 0);
+    }
+    state.set_pressed(Button::Mode, buf[38] != 0);
+    state.left_stick = Stick {
+        x: stick_axis(buf[40]),
+        y: stick_axis(buf[41]),
+    };
+    state.right_stick = Stick {
+        x: stick_axis(buf[42]),
+        y: stick_axis(buf[43]),
+    };
+    state.right_trigger = buf[54] as f32 / 255.0;
+    state.left_trigger = buf[55] as f32 / 255.0;
+    Ok((slot, Some(state)))
+}
+
+/// A controller read from a DSU (cemuhook) server, such as DS4Windows or a phone
+/// app.
+pub struct DsuInput {
+    socket: UdpSocket,
+    pub name: String,
+    pub server: SocketAddr,
+    pub slot: u8,
+    last_request: Option<Instant>,
+    last_data: Option<Instant>,
+    active: bool,
+    state: ControllerState,
+}
+
+impl DsuInput {
+    /// Set up a client for the server described by `config`.
+    pub fn open(config: &DsuConfig) -> Result<Self> {
+        // Bind to the same address family as the server, so IPv6 servers work too.
+        let local: SocketAddr = if config.server.is_ipv6() {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local)
+            .and_then(|s| s.connect(config.server).map(|_| s))
+            .with_context(|| format!("Failed to connect to DSU server {}", config.server))?;
+        socket.set_nonblocking(true)?;
+        Ok(DsuInput {
+            socket,
+            name: config.name.clone(),
+            server: config.server,
+            slot: config.slot,
+            last_request: None,
+            last_data: None,
+            active: false,
+            state: ControllerState::default(),
+        })
+    }
+
+    /// Whether the server is sending data for a connected controller.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn state(&self) -> ControllerState {
+        self.state.clone()
+    }
+
+    /// Ask the server to send data for our slot.
+    fn send_request(&self) {
+        let mut buf = Vec::with_capacity(28);
+        buf.extend_from_slice(CLIENT_MAGIC);
+        buf.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        buf.extend_from_slice(&12u16.to_le_bytes());
+        // The CRC, filled in below.
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&process::id().to_le_bytes());
+        buf.extend_from_slice(&MSG_DATA.to_le_bytes());
+        // Register for a single slot, rather than by MAC address.
+        buf.extend_from_slice(&[1, self.slot]);
+        buf.extend_from_slice(&[0; 6]);
+        let crc = crc32(&buf);
+        buf[8..12].copy_from_slice(&crc.to_le_bytes());
+        if let Err(e) = self.socket.send(&buf) {
+            debug!("Failed to send DSU request to {}: {e}", self.server);
+        }
+    }
+
+    /// Read any pending messages, and ask for more data when it's time to.
+    pub fn poll(&mut self) -> NetworkEvent {
+        let now = Instant::now();
+        if self
+            .last_request
+            .is_none_or(|t| now - t >= REQUEST_INTERVAL)
+        {
+            self.send_request();
+            self.last_request = Some(now);
+        }
+        let mut event = NetworkEvent::Nothing;
+        let mut buf = [0; 256];
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    // The server not running shows up here as a refused connection.
+                    debug!("Error receiving from DSU server {}: {e}", self.server);
+                    break;
+                }
+            };
+            let state = match parse_data(&buf[..len]) {
+                Ok((slot, state)) if slot == self.slot => state,
+                Ok(_) => continue,
+                Err(e) => {
+                    debug!("Ignoring message from DSU server {}: {e:#}", self.server);
+                    continue;
+                }
+            };
+            let Some(state) = state else {
+                if self.active {
+                    self.active = false;
+                    self.state = ControllerState::default();
+                    event = NetworkEvent::Disconnected;
+                }
+                continue;
+            };
+            self.last_data = Some(now);
+            self.state = state;
+            if !self.active {
+                self.active = true;
+                event = NetworkEvent::Connected;
+            } else if event == NetworkEvent::Nothing {
+                event = NetworkEvent::Changed;
+            }
+        }
+        if self.active && self.last_data.is_some_and(|t| now - t > TIMEOUT) {
+            self.active = false;
+            self.state = ControllerState::default();
+            return NetworkEvent::Disconnected;
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// A controller data message for `slot`, with its CRC filled in.
+    fn data_message(slot: u8, connected: bool, fill: impl FnOnce(&mut [u8])) -> Vec<u8> {
+        let mut buf = vec![0; DATA_LEN];
+        buf[..4].copy_from_slice(SERVER_MAGIC);
+        buf[4..6].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        buf[6..8].copy_from_slice(&((DATA_LEN - HEADER_LEN) as u16).to_le_bytes());
+        buf[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&MSG_DATA.to_le_bytes());
+        buf[20] = slot;
+        buf[21] = if connected { SLOT_CONNECTED } else { 0 };
+        fill(&mut buf);
+        let crc = crc32(&buf);
+        buf[8..12].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Wait for a request from the client, check it, and reply with `messages`.
+    fn serve(server: &UdpSocket, slot: u8, messages: &[Vec<u8>]) {
+        let mut buf = [0; 256];
+        let (len, client) = server.recv_from(&mut buf).unwrap();
+        let request = &buf[..len];
+        assert_eq!(&request[..4], CLIENT_MAGIC);
+        let mut unsigned = request.to_vec();
+        unsigned[8..12].fill(0);
+        assert_eq!(crc32(&unsigned), request.pread_with::<u32>(8, LE).unwrap());
+        assert_eq!(request.pread_with::<u32>(HEADER_LEN, LE).unwrap(), MSG_DATA);
+        assert_eq!(request[20..22], [1, slot]);
+        for message in messages {
+            server.send_to(message, client).unwrap();
+        }
+    }
+
+    /// Poll until something happens, or give up after a second.
+    fn poll(input: &mut DsuInput) -> NetworkEvent {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            let event = input.poll();
+            if event != NetworkEvent::Nothing || Instant::now() > deadline {
+                return event;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn crc() {
+        // The check value for CRC-32/ISO-HDLC, which zlib uses.
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn mock_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let config = DsuConfig {
+            name: "test".to_owned(),
+            server: server.local_addr().unwrap(),
+            slot: 1,
+        };
+        let mut input = DsuInput::open(&config).unwrap();
+        // Nothing is sent until the first poll.
+        assert_eq!(input.poll(), NetworkEvent::Nothing);
+
+        let mut bad_crc = data_message(1, true, |buf| buf[36] = 0x08);
+        bad_crc[8] ^= 1;
+        let other_slot = data_message(0, true, |buf| buf[36] = 0x08);
+        let data = data_message(1, true, |buf| {
+            // Select and d-pad up, then South and R, then Home.
+            buf[36] = 0x11;
+            buf[37] = 0x48;
+            buf[38] = 1;
+            // Left stick all the way right and up, right stick centered.
+            buf[40..44].copy_from_slice(&[255, 255, 128, 128]);
+            buf[54] = 255;
+            // Motion data, which isn't used.
+            buf[76..80].copy_from_slice(&1.0f32.to_le_bytes());
+        });
+        serve(&server, 1, &[bad_crc, other_slot, data]);
+        assert_eq!(poll(&mut input), NetworkEvent::Connected);
+        let state = input.state();
+        for button in [
+            Button::Select,
+            Button::DPadUp,
+            Button::South,
+            Button::RightTrigger,
+            Button::Mode,
+        ] {
+            assert!(state.is_pressed(button), "{button:?} isn't pressed");
+        }
+        assert!(!state.is_pressed(Button::Start));
+        assert_eq!(state.left_stick, Stick { x: 1.0, y: 1.0 });
+        assert_eq!(state.right_stick, Stick { x: 0.0, y: 0.0 });
+        assert_eq!(state.right_trigger, 1.0);
+        assert_eq!(state.left_trigger, 0.0);
+        assert_eq!(state.motion, None);
+
+        // The server says the controller has gone.
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        input.last_request = None;
+        input.poll();
+        serve(&server, 1, &[data_message(1, false, |_| {})]);
+        assert_eq!(poll(&mut input), NetworkEvent::Disconnected);
+        assert_eq!(input.state(), ControllerState::default());
+    }
+}
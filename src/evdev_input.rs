@@ -0,0 +1,204 @@
+use anyhow::{Context, Result};
+use evdev::{Device, InputEventKind};
+use gilrs::{Axis, Button};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use crate::config::EvdevConfig;
+use crate::controller_state::ControllerState;
+use crate::input_device::InputDevice;
+use crate::raw_mapping::{Binding, Bindings};
+
+/// How far an axis bound to a button has to move for the button to be pressed,
+/// from -1.0 to 1.0. This matches gilrs' default.
+const AXIS_PRESSED: f32 = 0.5;
+
+/// The range and current value of an absolute axis.
+#[derive(Clone, Copy, Debug)]
+pub struct AbsState {
+    pub value: i32,
+    pub min: i32,
+    pub max: i32,
+}
+
+impl AbsState {
+    /// The value scaled to -1.0..=1.0, the same way gilrs does it.
+    pub fn normalized(&self) -> f32 {
+        let mut range = self.max as f32 - self.min as f32;
+        let mut value = self.value as f32 - self.min as f32;
+        if range == 0.0 {
+            return 0.0;
+        }
+        // Put the center of axes with an even number of values (e.g. 0-255) at 0.0.
+        if (self.max - self.min) % 2 == 1 {
+            range += 1.0;
+            value += 1.0;
+        }
+        (value / range * 2.0 - 1.0).clamp(-1.0, 1.0)
+    }
+}
+
+/// A controller that's read directly from its evdev device node instead of through gilrs.
+pub struct EvdevController {
+    device: Device,
+    pub info: InputDevice,
+    /// The device node that the configured path resolves to, like `/dev/input/event3`.
+    pub node: PathBuf,
+    bindings: Bindings,
+    /// The `EV_KEY` codes that are currently pressed.
+    pressed: HashSet<u16>,
+    /// The state of each `EV_ABS` axis, by code.
+    abs: HashMap<u16, AbsState>,
+}
+
+impl EvdevController {
+    /// Open the device described by `config`, and grab it if configured to.
+    pub fn open(config: &EvdevConfig) -> Result<Self> {
+        let path = &config.path;
+        let mut device =
+            Device::open(path).with_context(|| format!("Failed to open evdev device: {path:?}"))?;
+        set_nonblocking(&device).with_context(|| format!("Failed to set up {path:?}"))?;
+        let node = fs::canonicalize(path).with_context(|| format!("Failed to resolve {path:?}"))?;
+        let info = InputDevice::new(path.clone(), &device);
+        let bindings = Bindings::new(&info, config.guess, &config.buttons, &config.axes)
+            .with_context(|| format!("Can't map {path:?}"))?;
+        if config.grab {
+            device
+                .grab()
+                .with_context(|| format!("Failed to grab {path:?}"))?;
+        }
+        let pressed = device.get_key_state()?.iter().map(|k| k.code()).collect();
+        let abs_state = device.get_abs_state()?;
+        let abs = info
+            .axes
+            .iter()
+            .map(|code| {
+                let info = abs_state[*code as usize];
+                let state = AbsState {
+                    value: info.value,
+                    min: info.minimum,
+                    max: info.maximum,
+                };
+                (*code, state)
+            })
+            .collect();
+        Ok(EvdevController {
+            device,
+            info,
+            node,
+            bindings,
+            pressed,
+            abs,
+        })
+    }
+
+    /// Read any pending events from the device. Returns whether any inputs changed.
+    ///
+    /// An error means the device has gone away.
+    pub fn poll(&mut self) -> io::Result<bool> {
+        let mut changed = false;
+        loop {
+            let events = match self.device.fetch_events() {
+                Ok(events) => events,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(changed),
+                Err(e) => return Err(e),
+            };
+            for event in events {
+                match event.kind() {
+                    InputEventKind::Key(key) => {
+                        if event.value() == 0 {
+                            self.pressed.remove(&key.code());
+                        } else {
+                            self.pressed.insert(key.code());
+                        }
+                        changed = true;
+                    }
+                    InputEventKind::AbsAxis(axis) => {
+                        if let Some(state) = self.abs.get_mut(&axis.0) {
+                            state.value = event.value();
+                            changed = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn abs_value(&self, code: u16) -> f32 {
+        self.abs.get(&code).map(AbsState::normalized).unwrap_or(0.0)
+    }
+
+    /// Whether `binding` is active, for bindings that act as buttons.
+    fn binding_pressed(&self, binding: Binding) -> bool {
+        match binding {
+            Binding::Key(code) => self.pressed.contains(&code),
+            Binding::Abs(code) => self.abs_value(code) > AXIS_PRESSED,
+            Binding::Hat { code, positive } => {
+                let value = self.abs.get(&code).map(|s| s.value).unwrap_or(0);
+                if positive {
+                    value > 0
+                } else {
+                    value < 0
+                }
+            }
+        }
+    }
+
+    /// The current state of the device's inputs.
+    pub fn state(&self) -> ControllerState {
+        let mut state = ControllerState::default();
+        for (button, binding) in &self.bindings.buttons {
+            state.set_pressed(*button, self.binding_pressed(*binding));
+            // Triggers on an axis are analog, from fully released at one end of the
+            // axis to fully pulled at the other.
+            if let Binding::Abs(code) = binding {
+                let value = (self.abs_value(*code) + 1.0) / 2.0;
+                match button {
+                    Button::LeftTrigger2 => state.left_trigger = value,
+                    Button::RightTrigger2 => state.right_trigger = value,
+                    _ => {}
+                }
+            }
+        }
+        for (axis, binding) in &self.bindings.axes {
+            let value = match *binding {
+                Binding::Abs(code) => self.abs_value(code),
+                binding => {
+                    if self.binding_pressed(binding) {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                }
+            };
+            // evdev Y axes are positive going down, ours are positive going up.
+            match axis {
+                Axis::LeftStickY | Axis::RightStickY => state.set_axis(*axis, -value),
+                _ => state.set_axis(*axis, value),
+            }
+        }
+        state
+    }
+}
+
+/// Make reads from `device` return `WouldBlock` instead of waiting for input.
+pub fn set_nonblocking(device: &Device) -> io::Result<()> {
+    let fd = device.as_raw_fd();
+    // SAFETY: `fd` is a valid file descriptor owned by `device`.
+    let res = unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            flags
+        } else {
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)
+        }
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
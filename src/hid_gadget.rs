@@ -2,28 +2,70 @@ use anyhow::{anyhow, bail, Context, Result};
 use log::{debug, error, info};
 use scroll::{Pwrite, LE};
 use std::fs::{self, File, OpenOptions};
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
-use std::os::unix::fs::symlink;
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::prelude::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use crate::config::{parse_language, Output, UsbConfig, UsbStrings};
+use crate::udc::UDC_CLASS_PATH;
+use crate::uhid;
 use crate::HIDGamepad;
 
 const CONFIGFS_GADGET_PATH: &str = "/sys/kernel/config/usb_gadget/";
 const GADGET_NAME: &str = "gadget_gamepads";
+/// The name of the gadget's only configuration, unless it's overridden.
+const CONFIGURATION_NAME: &str = "USB Gadget Gamepads";
+/// The language ID for US English, which every gadget has strings for.
+const LANGUAGE_EN_US: u16 = 0x409;
+/// How often to log each device's report counts.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
 struct HIDGadgetDevice<G: HIDGamepad> {
     function_name: String,
     hidg_path: String,
+    /// The uhid device standing in for the hidg device, when the output is `Output::Uhid`.
+    uhid: Option<File>,
     _gamepad: PhantomData<G>,
 }
 
+/// Counts of the reports written to a gadget device.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReportStats {
+    pub sent: u64,
+    /// Reports that weren't sent because they were the same as the last one.
+    pub skipped: u64,
+    /// Reports that weren't sent because a newer one replaced them while waiting
+    /// for the coalescing window to end, or for the host to start reading again.
+    pub coalesced: u64,
+    /// Times the host stopped reading reports for a while.
+    pub blocked: u64,
+}
+
 pub struct HIDGadgetDeviceFile<G: HIDGamepad> {
     hidg: File,
     index: usize,
+    /// The last report sent to the device.
     buf: Vec<u8>,
+    /// When the last report was sent, or `None` if nothing has been sent yet.
+    last_sent: Option<Instant>,
+    /// How long to wait after sending a report before sending another one.
+    coalesce: Option<Duration>,
+    /// The latest report, if it's waiting for the coalescing window to end or for
+    /// the host to start reading again.
+    pending: Option<Vec<u8>>,
+    /// What caused the pending report, for recordings. It's kept after the report
+    /// is sent, so that it can be recorded along with it.
+    pending_cause: String,
+    /// When writes started failing because the host stopped reading reports, if
+    /// it has.
+    blocked_since: Option<Instant>,
+    stats: ReportStats,
+    stats_logged: Instant,
     dev: HIDGadgetDevice<G>,
 }
 
@@ -32,35 +74,396 @@ impl<G: HIDGamepad> HIDGadgetDeviceFile<G> {
         &self.dev.hidg_path
     }
 
-    /// Write the input report to the device.
-    pub fn write_report(&mut self, report: G::Report) -> Result<()> {
-        self.buf
-            .as_mut_slice()
+    /// The index of this device within its gadget.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The last input report written to the device, as it was sent.
+    pub fn last_report(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Whether a report is waiting to be sent.
+    pub fn has_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// What caused the pending report, or the last one that was pending.
+    pub fn pending_cause(&self) -> &str {
+        &self.pending_cause
+    }
+
+    /// Record what caused the pending report, so it can be recorded when it's sent.
+    pub fn set_pending_cause(&mut self, cause: &str) {
+        cause.clone_into(&mut self.pending_cause);
+    }
+
+    pub fn stats(&self) -> ReportStats {
+        self.stats
+    }
+
+    /// Set how long to wait after sending a report before sending another one.
+    /// Reports written in the meantime are combined into one.
+    pub fn set_coalesce(&mut self, window: Option<Duration>) {
+        self.coalesce = window;
+    }
+
+    fn serialize(report: G::Report) -> Result<Vec<u8>> {
+        let mut buf = vec![0; G::report_size()];
+        buf.as_mut_slice()
             .pwrite_with(report, 0, LE)
             .map_err(|_| anyhow!("Error writing report"))?;
-        debug!("write_report: {:?}", self.buf.as_slice());
-        let written = self.hidg.write(&self.buf)?;
-        if written != G::report_size() {
-            bail!("Didn't write full report");
+        Ok(buf)
+    }
+
+    /// Write the input report to the device. Returns whether it was sent now.
+    ///
+    /// Reports that are the same as the last one sent are skipped, and reports
+    /// written during the coalescing window are held until it ends.
+    pub fn write_report(&mut self, report: G::Report) -> Result<bool> {
+        let report = Self::serialize(report)?;
+        self.submit(report)
+    }
+
+    /// Write the input report to the device now, even if it's the same as the last one.
+    /// Returns whether it was sent, which it won't have been if the host isn't reading.
+    pub fn force_write_report(&mut self, report: G::Report) -> Result<bool> {
+        let report = Self::serialize(report)?;
+        self.pending = None;
+        self.send(report)
+    }
+
+    /// Write an input report that's already been serialized, like one from a recording.
+    /// Returns whether it was sent now, like `write_report`.
+    pub fn write_report_bytes(&mut self, report: &[u8]) -> Result<bool> {
+        self.check_len(report)?;
+        self.submit(report.to_vec())
+    }
+
+    /// Write an input report that's already been serialized now, even if it's the
+    /// same as the last one. Returns whether it was sent, like `force_write_report`.
+    pub fn force_write_report_bytes(&mut self, report: &[u8]) -> Result<bool> {
+        self.check_len(report)?;
+        self.pending = None;
+        self.send(report.to_vec())
+    }
+
+    fn check_len(&self, report: &[u8]) -> Result<()> {
+        if report.len() != self.buf.len() {
+            bail!(
+                "Report is {} bytes, expected {}",
+                report.len(),
+                self.buf.len()
+            );
         }
         Ok(())
     }
 
-    /// Check if an output report is available from the device, and return it if so.
-    /// TODO: actually implement this, probably using nix::poll::poll
-    pub fn check_read_report(&mut self) -> Result<()> {
+    fn submit(&mut self, report: Vec<u8>) -> Result<bool> {
+        let Some(last_sent) = self.last_sent else {
+            return self.send(report);
+        };
+        if report == self.buf {
+            // Anything that was pending has been undone.
+            self.pending = None;
+            self.stats.skipped += 1;
+            return Ok(false);
+        }
+        // Only the latest report matters while the host isn't reading, so don't
+        // try to write each one.
+        if self.blocked_since.is_some() || self.coalesce.is_some_and(|w| last_sent.elapsed() < w) {
+            if self.pending.replace(report).is_some() {
+                self.stats.coalesced += 1;
+            }
+            return Ok(false);
+        }
+        self.pending = None;
+        self.send(report)
+    }
+
+    /// Send the pending report if the coalescing window has ended. Returns whether
+    /// a report was sent, which it won't have been if the host still isn't reading.
+    pub fn flush_pending(&mut self) -> Result<bool> {
+        let ready = self
+            .last_sent
+            .zip(self.coalesce)
+            .is_none_or(|(last_sent, w)| last_sent.elapsed() >= w);
+        match self.pending.take() {
+            Some(report) if ready => self.send(report),
+            pending => {
+                self.pending = pending;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Write `report` to the device. If the host isn't reading reports, it's kept as
+    /// the pending report instead, and `false` is returned.
+    fn send(&mut self, report: Vec<u8>) -> Result<bool> {
+        debug!("write_report: {report:?}");
+        match self.write_raw(&report) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if self.blocked_since.is_none() {
+                    info!(
+                        "{}: the host isn't reading reports, holding the latest one until it does",
+                        self.path()
+                    );
+                    self.blocked_since = Some(Instant::now());
+                    self.stats.blocked += 1;
+                }
+                self.pending = Some(report);
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(since) = self.blocked_since.take() {
+            info!(
+                "{}: the host is reading reports again after {:?}",
+                self.path(),
+                since.elapsed()
+            );
+        }
+        self.buf = report;
+        self.last_sent = Some(Instant::now());
+        self.stats.sent += 1;
+        if self.stats_logged.elapsed() >= STATS_INTERVAL {
+            self.log_stats();
+        }
+        Ok(true)
+    }
+
+    fn log_stats(&mut self) {
+        let stats = self.stats;
+        debug!(
+            "{}: {} reports sent, {} skipped as duplicates, {} coalesced, {} blocked",
+            self.path(),
+            stats.sent,
+            stats.skipped,
+            stats.coalesced,
+            stats.blocked
+        );
+        self.stats_logged = Instant::now();
+    }
+
+    /// Write an input report that's already been serialized. Fails with `WouldBlock`
+    /// if the host isn't reading reports.
+    fn write_raw(&mut self, report: &[u8]) -> io::Result<()> {
+        let event;
+        let data = if self.dev.uhid.is_some() {
+            event = uhid::input_event(report);
+            &event
+        } else {
+            report
+        };
+        let written = self.hidg.write(data)?;
+        if written != data.len() {
+            return Err(io::Error::other("Didn't write full report"));
+        }
         Ok(())
     }
+
+    /// Open the hidg device again, after the gadget has been rebound to its UDC.
+    ///
+    /// The next report is sent even if it's the same as the last one, since the host
+    /// has forgotten it.
+    pub fn reopen(&mut self) -> Result<()> {
+        if self.dev.uhid.is_some() {
+            return Ok(());
+        }
+        self.hidg = open_hidg(&self.dev.hidg_path)
+            .with_context(|| format!("Failed to reopen hidg device '{}'", self.path()))?;
+        self.last_sent = None;
+        self.pending = None;
+        self.blocked_since = None;
+        Ok(())
+    }
+
+    /// Check if an output report is available from the device, and return it if so.
+    ///
+    /// The gamepad's replies to the report, if it has any, are written before returning.
+    pub fn check_read_report(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; uhid::EVENT_SIZE];
+        loop {
+            if !poll_readable(&self.hidg, Duration::ZERO)? {
+                return Ok(None);
+            }
+            let len = self.hidg.read(&mut buf)?;
+            if len == 0 {
+                bail!("{} was closed", self.path());
+            }
+            let report = if self.dev.uhid.is_some() {
+                match uhid::output_report(&buf[..len]) {
+                    Some(report) => report.to_vec(),
+                    None => continue,
+                }
+            } else {
+                buf[..len].to_vec()
+            };
+            debug!("read_report: {report:?}");
+            for reply in G::handle_output_report(&report) {
+                debug!("reply: {reply:?}");
+                self.write_raw(&reply)?;
+            }
+            return Ok(Some(report));
+        }
+    }
+
+    /// Use `file` as a hidg device, for talking to something that pretends to be the
+    /// host. `file` must keep the boundaries between reports, like a `SOCK_SEQPACKET`
+    /// socket does.
+    pub fn with_file(file: File, index: usize, path: &str) -> Self {
+        HIDGadgetDeviceFile {
+            hidg: file,
+            index,
+            buf: vec![0; G::report_size()],
+            last_sent: None,
+            coalesce: None,
+            pending: None,
+            pending_cause: String::new(),
+            blocked_since: None,
+            stats: ReportStats::default(),
+            stats_logged: Instant::now(),
+            dev: HIDGadgetDevice {
+                function_name: path.to_owned(),
+                hidg_path: path.to_owned(),
+                uhid: None,
+                _gamepad: Default::default(),
+            },
+        }
+    }
+}
+
+/// Create a connected pair of `SOCK_SEQPACKET` sockets, one of which can stand in for
+/// a hidg device with `HIDGadgetDeviceFile::with_file`.
+pub fn socket_pair() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors socketpair returns.
+    let res = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: socketpair succeeded, so these are open descriptors that nothing else owns.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Wait up to `timeout` for `file` to have something to read. Returns whether it does.
+pub fn poll_readable(file: &impl AsRawFd, timeout: Duration) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `fd` points to a single valid pollfd.
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+    let res = unsafe { libc::poll(&mut fd, 1, timeout) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd.revents & libc::POLLIN != 0)
 }
 
 #[derive(Debug)]
 pub struct HIDGadget<G: HIDGamepad> {
     pub path: PathBuf,
+    output: Output,
+    /// The name of the UDC the gadget is bound to, if it's a USB gadget.
+    udc: Option<String>,
+    /// The languages the gadget has strings for, as the names of their directories.
+    languages: Vec<String>,
+    /// The coalescing window for devices when they're taken.
+    coalesce: Option<Duration>,
     devices: Vec<Option<HIDGadgetDevice<G>>>,
 }
 
+/// The strings the gadget reports in one language, with any overrides applied.
+#[derive(Clone)]
+struct StringTable {
+    language: u16,
+    manufacturer: String,
+    product: String,
+    serial_number: String,
+    configuration: String,
+}
+
+impl StringTable {
+    fn overridden(&self, language: u16, strings: &UsbStrings) -> Self {
+        let or = |s: &Option<String>, default: &String| s.as_ref().unwrap_or(default).clone();
+        StringTable {
+            language,
+            manufacturer: or(&strings.manufacturer, &self.manufacturer),
+            product: or(&strings.product, &self.product),
+            serial_number: or(&strings.serial_number, &self.serial_number),
+            configuration: or(&strings.configuration, &self.configuration),
+        }
+    }
+
+    /// The name of the table's directory in configfs, like `0x409`.
+    fn dir_name(&self) -> String {
+        format!("0x{:x}", self.language)
+    }
+}
+
+/// The gadget's string tables: US English first, then any other languages in `usb`.
+fn string_tables<G: HIDGamepad>(usb: &UsbConfig) -> Result<Vec<StringTable>> {
+    let defaults = StringTable {
+        language: LANGUAGE_EN_US,
+        manufacturer: G::MANUFACTURER.to_owned(),
+        product: G::PRODUCT.to_owned(),
+        serial_number: usb
+            .serial_number
+            .clone()
+            .unwrap_or_else(|| G::SERIAL_NUMBER.to_owned()),
+        configuration: CONFIGURATION_NAME.to_owned(),
+    };
+    let mut overrides = vec![];
+    for (language, strings) in &usb.strings {
+        let language = parse_language(language)?;
+        if overrides.iter().any(|(l, _)| *l == language) {
+            bail!("More than one USB string table for language 0x{language:x}");
+        }
+        overrides.push((language, strings));
+    }
+    let en_us = match overrides.iter().find(|(l, _)| *l == LANGUAGE_EN_US) {
+        Some((_, strings)) => defaults.overridden(LANGUAGE_EN_US, strings),
+        None => defaults,
+    };
+    let mut tables = vec![en_us.clone()];
+    for (language, strings) in overrides {
+        if language != LANGUAGE_EN_US {
+            tables.push(en_us.overridden(language, strings));
+        }
+    }
+    Ok(tables)
+}
+
+/// The device release number to identify as: the configured one, or `G`'s own.
+fn bcd_device<G: HIDGamepad>(usb: &UsbConfig) -> Result<u16> {
+    match usb.bcd_device {
+        Some(version) => Ok(version),
+        None => Ok(uhid::parse_id(G::BCD_DEVICE)? as u16),
+    }
+}
+
+/// Format a version number like `0x0200` the way configfs takes it.
+fn bcd_string(version: u16) -> Vec<u8> {
+    format!("0x{version:04x}").into_bytes()
+}
+
 impl<G: HIDGamepad> Drop for HIDGadget<G> {
     fn drop(&mut self) {
+        // uhid devices go away when their files are closed.
+        if self.output == Output::Uhid {
+            return;
+        }
         // Empty out the UDC file to disconnect the device
         fs::write(&self.path.join("UDC"), b"").unwrap();
         // The ordering of these is important:
@@ -75,21 +478,32 @@ impl<G: HIDGamepad> Drop for HIDGadget<G> {
                 error!("Gadget device {i} still in use while cleaning up!");
             }
         }
-        for item in &[
-            // Remove config strings
-            "configs/c.1/strings/0x409",
-            // Remove config
-            "configs/c.1",
-            // Remove gadget strings
-            "strings/0x409",
-        ] {
-            fs::remove_dir(&self.path.join(item)).unwrap()
+        // Remove config strings
+        for language in &self.languages {
+            fs::remove_dir(self.path.join("configs/c.1/strings").join(language)).unwrap()
+        }
+        // Remove config
+        fs::remove_dir(self.path.join("configs/c.1")).unwrap();
+        // Remove gadget strings
+        for language in &self.languages {
+            fs::remove_dir(self.path.join("strings").join(language)).unwrap()
         }
         // Finally, remove the entire gadget dir
         fs::remove_dir(&self.path).unwrap()
     }
 }
 
+/// Open a hidg device read+write. Writes mustn't block, or a host that stops
+/// reading (like a Switch that's gone to sleep) would hang the daemon.
+fn open_hidg(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .create(false)
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
 fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
     fs::write(path, contents).with_context(|| format!("Failed to write to: {path:?}"))?;
     Ok(())
@@ -122,7 +536,7 @@ fn create_gadget_function<G: HIDGamepad>(
     info!("Adding symlink from function to {target:?}");
     symlink(&path, &target)?;
     // Read the device number of the hidg device so we can open it
-    let dev_num = fs::read_to_string(&path.join("dev"))?;
+    let dev_num = fs::read_to_string(path.join("dev"))?;
     let hidg_path = if let Some((major, minor)) = dev_num.trim_end().split_once(':') {
         if major == "239" {
             format!("/dev/hidg{minor}")
@@ -135,44 +549,55 @@ fn create_gadget_function<G: HIDGamepad>(
     Ok(HIDGadgetDevice {
         function_name: name.to_owned(),
         hidg_path,
+        uhid: None,
         _gamepad: Default::default(),
     })
 }
 
 impl<G: HIDGamepad> HIDGadget<G> {
-    pub fn create(count: usize) -> Result<Self> {
+    /// Create a USB gadget with `count` gamepads, identifying itself to the host as
+    /// `G` with any overrides in `usb`.
+    pub fn create(count: usize, usb: &UsbConfig) -> Result<Self> {
+        let tables = string_tables::<G>(usb)?;
         let path = PathBuf::from(CONFIGFS_GADGET_PATH).join(GADGET_NAME);
         fs::create_dir(&path)?;
         info!("creating gadget at path: {path:?}");
         // Populate top-level attributes
         for (attr, contents) in &[
-            ("idVendor", G::VENDOR_ID),
-            ("idProduct", G::PRODUCT_ID),
-            ("bcdDevice", b"0x0100"), // v1.0.0
-            ("bcdUSB", b"0x0200"),    // USB 2.0
+            ("idVendor", G::VENDOR_ID.to_vec()),
+            ("idProduct", G::PRODUCT_ID.to_vec()),
+            ("bcdDevice", bcd_string(bcd_device::<G>(usb)?)),
+            (
+                "bcdUSB",
+                usb.bcd_usb.map_or_else(|| G::BCD_USB.to_vec(), bcd_string),
+            ),
         ] {
             write_file(&path.join(attr), contents)?;
         }
-        // Populate en-US strings
-        let strings = path.join("strings/0x409");
-        fs::create_dir_all(&strings)?;
-        for (attr, contents) in &[
-            ("serialnumber", b"0".as_slice()),
-            ("manufacturer", G::MANUFACTURER.as_bytes()),
-            ("product", G::PRODUCT.as_bytes()),
-        ] {
-            write_file(&strings.join(attr), contents)?;
+        // Populate the strings for each language
+        for table in &tables {
+            let strings = path.join("strings").join(table.dir_name());
+            fs::create_dir_all(&strings)?;
+            for (attr, contents) in &[
+                ("serialnumber", &table.serial_number),
+                ("manufacturer", &table.manufacturer),
+                ("product", &table.product),
+            ] {
+                write_file(&strings.join(attr), contents)?;
+            }
         }
         // Create config and populate a few attributes
         let config = path.join("configs/c.1");
         fs::create_dir_all(&config)?;
-        write_file(&config.join("MaxPower"), b"250")?;
-        let config_strings = config.join("strings/0x409");
-        fs::create_dir_all(&config_strings)?;
-        write_file(
-            &config_strings.join("configuration"),
-            b"USB Gadget Gamepads",
-        )?;
+        let max_power = usb.max_power.unwrap_or(G::MAX_POWER);
+        write_file(&config.join("MaxPower"), max_power.to_string())?;
+        // Bus powered, and able to wake the host with remote wakeup.
+        write_file(&config.join("bmAttributes"), b"0xa0")?;
+        for table in &tables {
+            let config_strings = config.join("strings").join(table.dir_name());
+            fs::create_dir_all(&config_strings)?;
+            write_file(&config_strings.join("configuration"), &table.configuration)?;
+        }
         // Create functions and link them to the config
         let mut devices = vec![];
         for i in 0..count {
@@ -183,7 +608,7 @@ impl<G: HIDGamepad> HIDGadget<G> {
 
         // Enable the gadget
         info!("Attempting to enable gadget");
-        let udc_entries = fs::read_dir("/sys/class/udc")?.collect::<Result<Vec<_>, _>>()?;
+        let udc_entries = fs::read_dir(UDC_CLASS_PATH)?.collect::<Result<Vec<_>, _>>()?;
         let udc = if let Some(entry) = udc_entries.first() {
             entry.file_name()
         } else {
@@ -192,57 +617,167 @@ impl<G: HIDGamepad> HIDGadget<G> {
         info!("udc: {udc:?}");
         write_file(&path.join("UDC"), udc.as_bytes())?;
 
-        Ok(HIDGadget { path, devices })
+        Ok(HIDGadget {
+            path,
+            output: Output::Gadget,
+            udc: Some(udc.to_string_lossy().into_owned()),
+            languages: tables.iter().map(StringTable::dir_name).collect(),
+            coalesce: None,
+            devices,
+        })
+    }
+
+    /// Create `count` uhid devices on this machine instead of a USB gadget, so the
+    /// reports can be watched locally with tools like evtest or jstest.
+    ///
+    /// Only `bcd_device` from `usb` applies, as the devices' version.
+    pub fn create_uhid(count: usize, usb: &UsbConfig) -> Result<Self> {
+        let version = bcd_device::<G>(usb)?;
+        let mut devices = vec![];
+        for i in 0..count {
+            let name = format!("uhid{i}");
+            let phys = format!("pizero-gadget-gamepads/{name}");
+            let file = uhid::create_device::<G>(&phys, version)?;
+            info!("Created uhid device {name}");
+            devices.push(Some(HIDGadgetDevice {
+                function_name: name.clone(),
+                hidg_path: name,
+                uhid: Some(file),
+                _gamepad: Default::default(),
+            }));
+        }
+        Ok(HIDGadget {
+            path: PathBuf::from(uhid::UHID_PATH),
+            output: Output::Uhid,
+            udc: None,
+            languages: vec![],
+            coalesce: None,
+            devices,
+        })
+    }
+
+    /// Create the emulated controllers on `output`, identifying as `G` with any
+    /// overrides in `usb` that apply to it.
+    pub fn create_for(output: Output, count: usize, usb: &UsbConfig) -> Result<Self> {
+        match output {
+            Output::Gadget => Self::create(count, usb),
+            Output::Uhid => Self::create_uhid(count, usb),
+        }
+    }
+
+    /// The name of the UDC the gadget is bound to, if it's a USB gadget.
+    pub fn udc(&self) -> Option<&str> {
+        self.udc.as_deref()
+    }
+
+    /// Unbind the gadget from its UDC and bind it again, so the host sees it unplugged
+    /// and plugged back in and enumerates it from scratch. Devices that are in use
+    /// need to be reopened afterwards.
+    pub fn rebind(&self) -> Result<()> {
+        let Some(udc) = &self.udc else {
+            bail!("Only USB gadgets can be rebound");
+        };
+        let udc_path = self.path.join("UDC");
+        write_file(&udc_path, b"")?;
+        write_file(&udc_path, udc.as_bytes())
+    }
+
+    /// Set the coalescing window for devices that are taken from now on.
+    pub fn set_coalesce(&mut self, window: Option<Duration>) {
+        self.coalesce = window;
     }
 
     pub fn device_count(&self) -> usize {
         self.devices.len()
     }
 
+    /// The path of the hidg device at `index`, if it's available.
+    pub fn device_path(&self, index: usize) -> Option<&str> {
+        self.devices
+            .get(index)
+            .and_then(|d| d.as_ref())
+            .map(|d| d.hidg_path.as_str())
+    }
+
+    /// Attempt to open and take the gadget device at `index` for use.
+    pub fn take_device_at(&mut self, index: usize) -> Result<HIDGadgetDeviceFile<G>> {
+        let device = match self.devices.get_mut(index) {
+            None => bail!("Gadget device index {index} is out of bounds"),
+            Some(device) => device,
+        };
+        let dev = match device.take() {
+            None => bail!("Gadget device {index} is already in use"),
+            Some(dev) => dev,
+        };
+        let file = match &dev.uhid {
+            Some(uhid) => uhid.try_clone(),
+            None => open_hidg(&dev.hidg_path),
+        };
+        match file {
+            Ok(hidg) => {
+                let buf = vec![0; G::report_size()];
+                Ok(HIDGadgetDeviceFile {
+                    hidg,
+                    index,
+                    buf,
+                    last_sent: None,
+                    coalesce: self.coalesce,
+                    pending: None,
+                    pending_cause: String::new(),
+                    blocked_since: None,
+                    stats: ReportStats::default(),
+                    stats_logged: Instant::now(),
+                    dev,
+                })
+            }
+            // There was an available device but we failed to open it, so put it back.
+            Err(e) => {
+                let msg = format!("Failed to open hidg device '{}': {}", &dev.hidg_path, e);
+                let _ = device.insert(dev);
+                Err(anyhow!(msg))
+            }
+        }
+    }
+
     /// Attempt to open and take the first available gadget device for use.
     pub fn take_device(&mut self) -> Result<HIDGadgetDeviceFile<G>> {
-        for (index, device) in self.devices.iter_mut().enumerate() {
-            let res = device.take().map(|dev| {
-                // Open the hidg device read+write
-                match OpenOptions::new()
-                    .create(false)
-                    .read(true)
-                    .write(true)
-                    .open(&dev.hidg_path)
-                {
-                    Ok(file) => (dev, Ok(file)),
-                    Err(e) => {
-                        let msg = format!("Failed to open hidg device '{}': {}", &dev.hidg_path, e);
-                        (dev, Err(anyhow!(msg)))
-                    }
-                }
-            });
-            match res {
-                // This device is already taken.
-                None => {}
-                // There was an available device but we failed to open it, so put it back.
-                Some((dev, Err(e))) => {
-                    error!("{}", e);
-                    let _ = device.insert(dev);
-                    // Don't return here, maybe we can open another available device.
-                }
-                // There was an available device here and we opened it, so return it.
-                Some((dev, Ok(hidg))) => {
-                    let buf = vec![0; G::report_size()];
-                    return Ok(HIDGadgetDeviceFile {
-                        hidg,
-                        index,
-                        buf,
-                        dev,
-                    });
-                }
+        self.take_device_avoiding(&[])
+    }
+
+    /// Attempt to open and take the first available gadget device for use, preferring
+    /// devices whose index is not in `reserved`.
+    ///
+    /// Reserved devices are only used if no other devices are available.
+    pub fn take_device_avoiding(&mut self, reserved: &[usize]) -> Result<HIDGadgetDeviceFile<G>> {
+        let available = (0..self.devices.len())
+            .filter(|i| self.devices[*i].is_some())
+            .collect::<Vec<_>>();
+        let (unreserved, reserved): (Vec<_>, Vec<_>) =
+            available.into_iter().partition(|i| !reserved.contains(i));
+        for index in unreserved.into_iter().chain(reserved) {
+            match self.take_device_at(index) {
+                Ok(device) => return Ok(device),
+                // Don't return here, maybe we can open another available device.
+                Err(e) => error!("{}", e),
             }
         }
         bail!("Couldn't find a usable gadget device")
     }
 
     /// Release device back into the pool of available devices.
-    pub fn release_device(&mut self, device: HIDGadgetDeviceFile<G>) -> Result<()> {
+    pub fn release_device(&mut self, mut device: HIDGadgetDeviceFile<G>) -> Result<()> {
+        // Whatever was waiting to be sent is the device's final state.
+        if let Some(report) = device.pending.take() {
+            match device.send(report) {
+                Ok(true) => {}
+                Ok(false) => debug!(
+                    "Dropped pending report for {}, the host isn't reading",
+                    device.path()
+                ),
+                Err(e) => debug!("Failed to send pending report to {}: {e}", device.path()),
+            }
+        }
+        device.log_stats();
         let HIDGadgetDeviceFile { index, dev, .. } = device;
         match self.devices.get_mut(index) {
             None => bail!(
@@ -258,3 +793,114 @@ impl<G: HIDGamepad> HIDGadget<G> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller_state::ControllerState;
+    use crate::hori_pokken::{HoriPokkenPad, HoriPokkenPadReport};
+    use gilrs::Button;
+    use std::thread;
+
+    fn device() -> (HIDGadgetDeviceFile<HoriPokkenPad>, File) {
+        let (device, host) = socket_pair().unwrap();
+        (
+            HIDGadgetDeviceFile::with_file(device, 0, "fake-hidg0"),
+            host,
+        )
+    }
+
+    fn report(buttons: &[Button]) -> HoriPokkenPadReport {
+        let mut state = ControllerState::default();
+        state.press(buttons);
+        HoriPokkenPad::fill_report(&state)
+    }
+
+    /// The reports the device has sent to the host so far.
+    fn received(host: &mut File) -> Vec<Vec<u8>> {
+        let mut reports = vec![];
+        let mut buf = [0; 64];
+        while poll_readable(host, Duration::ZERO).unwrap() {
+            let len = host.read(&mut buf).unwrap();
+            reports.push(buf[..len].to_vec());
+        }
+        reports
+    }
+
+    fn bytes(report: HoriPokkenPadReport) -> Vec<u8> {
+        HIDGadgetDeviceFile::<HoriPokkenPad>::serialize(report).unwrap()
+    }
+
+    #[test]
+    fn skips_duplicates() {
+        let (mut device, mut host) = device();
+        assert!(device.write_report(report(&[])).unwrap());
+        assert!(!device.write_report(report(&[])).unwrap());
+        assert!(device.write_report(report(&[Button::South])).unwrap());
+        assert!(!device.write_report(report(&[Button::South])).unwrap());
+        assert_eq!(
+            received(&mut host),
+            [bytes(report(&[])), bytes(report(&[Button::South]))]
+        );
+        let stats = device.stats();
+        assert_eq!((stats.sent, stats.skipped, stats.coalesced), (2, 2, 0));
+    }
+
+    #[test]
+    fn coalesces_within_window() {
+        let window = Duration::from_millis(50);
+        let (mut device, mut host) = device();
+        device.set_coalesce(Some(window));
+        assert!(device.write_report(report(&[])).unwrap());
+        // Reports written during the window are held, and only the latest is kept.
+        assert!(!device.write_report(report(&[Button::South])).unwrap());
+        assert!(!device.write_report(report(&[Button::East])).unwrap());
+        assert!(device.has_pending());
+        assert!(!device.flush_pending().unwrap());
+        assert_eq!(received(&mut host), [bytes(report(&[]))]);
+
+        thread::sleep(window);
+        assert!(device.flush_pending().unwrap());
+        assert!(!device.has_pending());
+        assert_eq!(received(&mut host), [bytes(report(&[Button::East]))]);
+        assert_eq!(device.last_report(), bytes(report(&[Button::East])));
+
+        // Going back to the last report sent undoes the pending one.
+        assert!(!device.write_report(report(&[Button::West])).unwrap());
+        assert!(!device.write_report(report(&[Button::East])).unwrap());
+        assert!(!device.has_pending());
+        thread::sleep(window);
+        assert!(!device.flush_pending().unwrap());
+        assert_eq!(received(&mut host), Vec::<Vec<u8>>::new());
+
+        let stats = device.stats();
+        assert_eq!((stats.sent, stats.skipped, stats.coalesced), (2, 1, 1));
+    }
+
+    #[test]
+    fn forced_writes_always_send() {
+        let (mut device, mut host) = device();
+        device.set_coalesce(Some(Duration::from_secs(60)));
+        assert!(device.write_report(report(&[])).unwrap());
+        // Neither the same report nor the coalescing window hold it back.
+        assert!(device.force_write_report(report(&[])).unwrap());
+        assert!(!device.write_report(report(&[Button::South])).unwrap());
+        assert!(device.force_write_report(report(&[Button::East])).unwrap());
+        // The pending report it replaced isn't sent later.
+        assert!(!device.has_pending());
+        assert!(device
+            .force_write_report_bytes(&bytes(report(&[Button::East])))
+            .unwrap());
+        assert_eq!(
+            received(&mut host),
+            [
+                bytes(report(&[])),
+                bytes(report(&[])),
+                bytes(report(&[Button::East])),
+                bytes(report(&[Button::East])),
+            ]
+        );
+        let stats = device.stats();
+        assert_eq!((stats.sent, stats.skipped, stats.coalesced), (4, 0, 0));
+    }
+}
@@ -1,10 +1,11 @@
+// The code generated by scroll's IOwrite derive trips this lint.
+#![allow(clippy::unused_unit)]
+
+use crate::controller_state::ControllerState;
 use crate::HIDGamepad;
-use gilrs::{
-    ev::{state::GamepadState, Code},
-    Axis, Button, Gamepad,
-};
+use gilrs::Button;
 use scroll::{IOwrite, Pwrite, SizeWith};
-use std::{collections::HashMap, fmt::Write};
+use std::fmt::Write;
 
 /// A HID descriptor that is compatible with the HORI Pokken Pad.
 ///
@@ -59,7 +60,7 @@ const HORI_POKKEN_PAD_DESCRIPTOR: &[u8] = &[
 ];
 
 /// A HID report for the HORI Pokken Pad that matches the above descriptor.
-#[derive(Debug, Default, PartialEq, Pwrite, IOwrite, SizeWith)]
+#[derive(Debug, PartialEq, Pwrite, IOwrite, SizeWith)]
 pub struct HoriPokkenPadReport {
     /// 14 button values + 2 unused bits.
     pub buttons: u16,
@@ -79,10 +80,24 @@ pub struct HoriPokkenPadReport {
     pub _vendor_spec: u8,
 }
 
+impl Default for HoriPokkenPadReport {
+    fn default() -> Self {
+        HoriPokkenPadReport {
+            buttons: 0,
+            dpad: DPAD_NEUTRAL,
+            lx: 128,
+            ly: 128,
+            rx: 128,
+            ry: 128,
+            _vendor_spec: 0,
+        }
+    }
+}
+
 impl std::fmt::Display for HoriPokkenPadReport {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_char('[')?;
-        for (i, name) in BUTTON_NAMES.into_iter().enumerate() {
+        for (i, name) in BUTTON_NAMES.iter().enumerate() {
             if self.buttons & (1 << (i as u8)) != 0 {
                 f.write_str(name)?;
             } else {
@@ -132,26 +147,51 @@ const BUTTON_ORDER: &[Button] = &[
     // Capture button is unmapped
 ];
 
-#[derive(Debug)]
-pub struct HoriPokkenPad;
+/// The d-pad value when no direction is pressed.
+const DPAD_NEUTRAL: u8 = 0x08;
 
-fn get_axis(axis: Axis, state: &GamepadState, map: &HashMap<Axis, Code>) -> u8 {
-    map.get(&axis)
-        .map(|c| {
-            let v = (state.value(*c) + 1.0) / 2.0 * 255.0;
-            if v.is_normal() {
-                v.round() as u8
-            } else {
-                128
-            }
-        })
-        .unwrap_or(128)
+/// The d-pad hat switch values, in order, as (up, right, down, left).
+const DPAD_DIRECTIONS: [(bool, bool, bool, bool); 8] = [
+    // Up
+    (true, false, false, false),
+    // Up-right
+    (true, true, false, false),
+    // Right
+    (false, true, false, false),
+    // Down-right
+    (false, true, true, false),
+    // Down
+    (false, false, true, false),
+    // Down-left
+    (false, false, true, true),
+    // Left
+    (false, false, false, true),
+    // Up-left
+    (true, false, false, true),
+];
+
+fn dpad_value(up: bool, right: bool, down: bool, left: bool) -> u8 {
+    DPAD_DIRECTIONS
+        .iter()
+        .position(|d| *d == (up, right, down, left))
+        .map(|i| i as u8)
+        // Default, no direction pressed.
+        .unwrap_or(DPAD_NEUTRAL)
 }
 
-fn get_button(button: Button, state: &GamepadState, map: &HashMap<Button, Code>) -> bool {
-    map.get(&button)
-        .map(|code| state.is_pressed(*code))
-        .unwrap_or(false)
+#[derive(Debug)]
+pub struct HoriPokkenPad;
+
+/// Convert a stick axis value from -1.0 to 1.0 into a report value.
+fn get_axis(value: f32) -> u8 {
+    let v = (value + 1.0) / 2.0 * 255.0;
+    // Not `is_normal`, which is false for 0.0 and would center a stick that's
+    // pushed all the way.
+    if v.is_finite() {
+        v.round() as u8
+    } else {
+        128
+    }
 }
 
 impl HIDGamepad for HoriPokkenPad {
@@ -163,57 +203,74 @@ impl HIDGamepad for HoriPokkenPad {
     const ANALOG_BUTTONS: bool = false;
     type Report = HoriPokkenPadReport;
 
-    fn fill_report(
-        gamepad: &Gamepad,
-        button_mapping: &HashMap<Button, Code>,
-        axis_mapping: &HashMap<Axis, Code>,
-    ) -> Self::Report {
-        let state = gamepad.state();
+    fn fill_report(state: &ControllerState) -> Self::Report {
         // Map buttons.
         let mut buttons = 0;
         for (i, b) in BUTTON_ORDER.iter().enumerate() {
-            if get_button(*b, state, button_mapping) {
+            if state.is_pressed(*b) {
                 buttons |= 1 << i;
             }
         }
-        // Map axes.
-        let lx = get_axis(Axis::LeftStickX, state, axis_mapping);
-        let ly = get_axis(Axis::LeftStickY, state, axis_mapping);
-        let rx = get_axis(Axis::RightStickX, state, axis_mapping);
-        let ry = get_axis(Axis::RightStickY, state, axis_mapping);
         // Map d-pad.
-        let up = get_button(Button::DPadUp, state, button_mapping);
-        let right = get_button(Button::DPadRight, state, button_mapping);
-        let down = get_button(Button::DPadDown, state, button_mapping);
-        let left = get_button(Button::DPadLeft, state, button_mapping);
-        let dpad = match (up, right, down, left) {
-            // Up
-            (true, false, false, false) => 0x00,
-            // Up-right
-            (true, true, false, false) => 0x01,
-            // Right
-            (false, true, false, false) => 0x02,
-            // Down-right
-            (false, true, true, false) => 0x03,
-            // Down
-            (false, false, true, false) => 0x04,
-            // Down-left
-            (false, false, true, true) => 0x05,
-            // Left
-            (false, false, false, true) => 0x06,
-            // Up-left
-            (true, false, false, true) => 0x07,
-            // Default, no direction pressed.
-            _ => 0x08,
-        };
+        let hat = state.hat;
+        let dpad = dpad_value(hat.up, hat.right, hat.down, hat.left);
         HoriPokkenPadReport {
             buttons,
             dpad,
-            lx,
-            ly,
-            rx,
-            ry,
+            lx: get_axis(state.left_stick.x),
+            ly: get_axis(state.left_stick.y),
+            rx: get_axis(state.right_stick.x),
+            ry: get_axis(state.right_stick.y),
             _vendor_spec: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller_state::{Hat, Stick};
+    use scroll::LE;
+
+    fn report_bytes(state: &ControllerState) -> Vec<u8> {
+        let mut buf = vec![0; HoriPokkenPad::report_size()];
+        buf.pwrite_with(HoriPokkenPad::fill_report(state), 0, LE)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn neutral_report() {
+        let bytes = report_bytes(&ControllerState::default());
+        assert_eq!(bytes, [0, 0, DPAD_NEUTRAL, 128, 128, 128, 128, 0]);
+    }
+
+    #[test]
+    fn buttons_and_dpad() {
+        let mut state = ControllerState::default();
+        // Switch Y, A and Home.
+        state.press(&[Button::West, Button::East, Button::Mode]);
+        state.hat = Hat {
+            down: true,
+            left: true,
+            ..Hat::default()
+        };
+        let bytes = report_bytes(&state);
+        assert_eq!(bytes[..3], [0b0000_0101, 0b0001_0000, 5]);
+    }
+
+    #[test]
+    fn sticks() {
+        let state = ControllerState {
+            left_stick: Stick { x: -1.0, y: 1.0 },
+            right_stick: Stick {
+                x: 0.5,
+                y: f32::NAN,
+            },
+            ..ControllerState::default()
+        };
+        let bytes = report_bytes(&state);
+        // A stick pushed all the way to -1.0 isn't centered.
+        assert_eq!(bytes[3..7], [0, 255, 191, 128]);
+    }
+}
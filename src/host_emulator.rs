@@ -0,0 +1,152 @@
+use anyhow::{anyhow, bail, Context, Result};
+use gilrs::Button;
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::controller_state::ControllerState;
+use crate::hid_gadget::{poll_readable, socket_pair, HIDGadgetDeviceFile};
+use crate::HIDGamepad;
+
+/// How long to wait for the device to send an expected report.
+const EXPECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A conversation with a gamepad, from the host's side.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    pub step: Vec<Step>,
+}
+
+/// One step of a script. Reports are written as hex bytes separated by spaces.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    /// Send an output report, as the host would.
+    Send(String),
+    /// Have the gamepad send an input report with these buttons pressed.
+    Press(Vec<Button>),
+    /// Check the next input report the gamepad sent. `??` matches any byte.
+    Expect(String),
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {path:?}"))
+    }
+}
+
+fn parse_bytes(s: &str) -> Result<Vec<u8>> {
+    s.split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16).map_err(|_| anyhow!("Bad byte: {b}")))
+        .collect()
+}
+
+/// Parse an expected report, where `None` is a byte that can be anything.
+fn parse_pattern(s: &str) -> Result<Vec<Option<u8>>> {
+    s.split_whitespace()
+        .map(|b| match b {
+            "??" => Ok(None),
+            b => u8::from_str_radix(b, 16)
+                .map(Some)
+                .map_err(|_| anyhow!("Bad byte: {b}")),
+        })
+        .collect()
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Plays the host (the Switch) to a gadget device backed by a socket instead of a
+/// real hidg device, so that conversations with the host can be tested on any machine.
+pub struct HostEmulator<G: HIDGamepad> {
+    device: HIDGadgetDeviceFile<G>,
+    /// The host's end of the socket.
+    host: File,
+}
+
+impl<G: HIDGamepad> HostEmulator<G> {
+    pub fn new() -> Result<Self> {
+        let (device, host) = socket_pair().context("Failed to create socket pair")?;
+        Ok(HostEmulator {
+            device: HIDGadgetDeviceFile::with_file(device, 0, "fake-hidg0"),
+            host,
+        })
+    }
+
+    /// Send an output report to the gamepad, and let it handle it.
+    pub fn send(&mut self, report: &[u8]) -> Result<()> {
+        self.host.write_all(report)?;
+        while self.device.check_read_report()?.is_some() {}
+        Ok(())
+    }
+
+    /// Have the gamepad send a report for `state`, unless it's the same as the last one.
+    pub fn write_state(&mut self, state: &ControllerState) -> Result<()> {
+        self.device.write_report(G::fill_report(state))?;
+        Ok(())
+    }
+
+    /// The next input report from the gamepad, if it sends one within `timeout`.
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        if !poll_readable(&self.host, timeout)? {
+            return Ok(None);
+        }
+        let mut buf = vec![0; 1024];
+        let len = self.host.read(&mut buf)?;
+        buf.truncate(len);
+        Ok(Some(buf))
+    }
+
+    /// Run `script`, failing at the first report that isn't what it expects.
+    ///
+    /// Input reports that the script doesn't expect are failures too.
+    pub fn run(&mut self, script: &Script) -> Result<()> {
+        for (i, step) in script.step.iter().enumerate() {
+            self.run_step(step)
+                .with_context(|| format!("Step {} failed: {step:?}", i + 1))?;
+        }
+        if let Some(report) = self.receive(Duration::ZERO)? {
+            bail!(
+                "Unexpected report after the last step: {}",
+                format_bytes(&report)
+            );
+        }
+        Ok(())
+    }
+
+    fn run_step(&mut self, step: &Step) -> Result<()> {
+        match step {
+            Step::Send(report) => self.send(&parse_bytes(report)?),
+            Step::Press(buttons) => {
+                let mut state = ControllerState::default();
+                state.press(buttons);
+                self.write_state(&state)
+            }
+            Step::Expect(pattern) => {
+                let pattern = parse_pattern(pattern)?;
+                let report = self
+                    .receive(EXPECT_TIMEOUT)?
+                    .ok_or_else(|| anyhow!("No report was sent"))?;
+                let matches = report.len() == pattern.len()
+                    && report
+                        .iter()
+                        .zip(&pattern)
+                        .all(|(b, p)| p.is_none_or(|p| p == *b));
+                if !matches {
+                    bail!("Got report: {}", format_bytes(&report));
+                }
+                Ok(())
+            }
+        }
+    }
+}
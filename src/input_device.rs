@@ -0,0 +1,101 @@
+use std::path::PathBuf;
+
+use crate::slot_affinity::controller_key;
+
+pub const EV_KEY: u16 = 0x01;
+pub const EV_ABS: u16 = 0x03;
+const BTN_MISC: u16 = 0x100;
+const BTN_MOUSE: u16 = 0x110;
+const BTN_JOYSTICK: u16 = 0x120;
+
+/// An evdev input device, with its buttons and axes in the order gilrs numbers
+/// them when it applies an SDL mapping.
+#[derive(Debug)]
+pub struct InputDevice {
+    pub path: PathBuf,
+    pub name: String,
+    /// The device's SDL GUID, formatted like `controller_key`.
+    pub guid: String,
+    /// Button codes (`EV_KEY`).
+    pub buttons: Vec<u16>,
+    /// Absolute axis codes (`EV_ABS`).
+    pub axes: Vec<u16>,
+}
+
+impl InputDevice {
+    /// Describe an open evdev device.
+    pub fn new(path: PathBuf, device: &evdev::Device) -> Self {
+        let id = device.input_id();
+        let keys = device
+            .supported_keys()
+            .map(|keys| keys.iter().map(|k| k.code()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        // gilrs puts the gamepad buttons first, then everything else.
+        let mut buttons = keys
+            .iter()
+            .copied()
+            .filter(|k| (BTN_MISC..BTN_MOUSE).contains(k))
+            .collect::<Vec<_>>();
+        buttons.extend(keys.iter().filter(|k| **k >= BTN_JOYSTICK));
+        buttons.extend(keys.iter().filter(|k| **k < BTN_MISC));
+        buttons.extend(
+            keys.iter()
+                .filter(|k| (BTN_MOUSE..BTN_JOYSTICK).contains(*k)),
+        );
+        InputDevice {
+            path,
+            name: device.name().unwrap_or_default().to_owned(),
+            guid: evdev_guid(id.bus_type().0, id.vendor(), id.product(), id.version()),
+            buttons,
+            axes: device
+                .supported_absolute_axes()
+                .map(|axes| axes.iter().map(|a| a.0).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// All of the input devices we can open.
+    pub fn enumerate() -> impl Iterator<Item = InputDevice> {
+        evdev::enumerate().map(|(path, device)| InputDevice::new(path, &device))
+    }
+
+    /// Find the input device for the gamepad with the SDL GUID `guid` and name `name`.
+    pub fn find(guid: &str, name: &str) -> Option<InputDevice> {
+        Self::enumerate().find(|d| d.guid == guid && d.name == name)
+    }
+
+    /// The SDL mapping value for a button or axis code, if this device has it.
+    pub fn binding(&self, kind: u16, code: u16) -> Option<String> {
+        let find = |list: &[u16]| list.iter().position(|c| *c == code);
+        match kind {
+            EV_KEY => find(&self.buttons).map(|i| format!("b{i}")),
+            EV_ABS => find(&self.axes).map(|i| format!("a{i}")),
+            _ => None,
+        }
+    }
+
+    /// Build an SDL mapping line for this device from its `fields`, which must end with a comma.
+    pub fn sdl_mapping(&self, fields: &str) -> String {
+        // Commas separate the fields of the mapping, so they can't be in the name.
+        let name = self.name.replace(',', " ");
+        format!("{},{name},{fields}platform:Linux,", self.guid)
+    }
+
+    /// Whether this device has an `EV_KEY` or `EV_ABS` code.
+    pub fn has(&self, kind: u16, code: u16) -> bool {
+        match kind {
+            EV_KEY => self.buttons.contains(&code),
+            EV_ABS => self.axes.contains(&code),
+            _ => false,
+        }
+    }
+}
+
+/// Format an input device ID the way SDL (and gilrs) build a GUID from it on Linux.
+fn evdev_guid(bus: u16, vendor: u16, product: u16, version: u16) -> String {
+    let mut bytes = [0; 16];
+    for (i, value) in [bus, vendor, product, version].into_iter().enumerate() {
+        bytes[i * 4..i * 4 + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    controller_key(bytes)
+}
@@ -0,0 +1,387 @@
+use anyhow::{anyhow, Context, Result};
+use evdev::{Device, InputEvent, InputEventKind, Key, RelativeAxisType};
+use gilrs::Button;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::KeyboardMouseConfig;
+use crate::controller_state::{ControllerState, Stick};
+use crate::evdev_input::set_nonblocking;
+use crate::input_device::InputDevice;
+
+/// The default bindings, for shooters: jump on space, fire and aim on the mouse buttons.
+const DEFAULT_BUTTONS: &[(Button, Key)] = &[
+    (Button::South, Key::KEY_SPACE),
+    (Button::East, Key::KEY_C),
+    (Button::West, Key::KEY_R),
+    (Button::North, Key::KEY_F),
+    (Button::LeftTrigger, Key::KEY_Q),
+    (Button::RightTrigger, Key::KEY_E),
+    (Button::LeftTrigger2, Key::BTN_RIGHT),
+    (Button::RightTrigger2, Key::BTN_LEFT),
+    (Button::Select, Key::KEY_TAB),
+    (Button::Start, Key::KEY_ESC),
+    (Button::Mode, Key::KEY_HOME),
+    (Button::LeftThumb, Key::KEY_LEFTSHIFT),
+    (Button::RightThumb, Key::BTN_MIDDLE),
+    (Button::DPadUp, Key::KEY_UP),
+    (Button::DPadDown, Key::KEY_DOWN),
+    (Button::DPadLeft, Key::KEY_LEFT),
+    (Button::DPadRight, Key::KEY_RIGHT),
+];
+
+/// How far back to look when working out how fast the mouse is moving.
+const MOUSE_WINDOW: Duration = Duration::from_millis(50);
+/// The mouse speed, in counts per second, that pushes the right stick all the way
+/// at a sensitivity of 1.0.
+const FULL_SPEED: f32 = 2000.0;
+
+/// Parse a key or mouse button code name like `KEY_SPACE` or `BTN_LEFT`.
+pub fn parse_key(name: &str) -> Result<u16> {
+    name.parse::<Key>()
+        .map(|k| k.code())
+        .map_err(|_| anyhow!("Unknown key: {name}"))
+}
+
+fn open_device(path: &Path, grab: bool) -> Result<Device> {
+    let mut device =
+        Device::open(path).with_context(|| format!("Failed to open evdev device: {path:?}"))?;
+    set_nonblocking(&device).with_context(|| format!("Failed to set up {path:?}"))?;
+    if grab {
+        device
+            .grab()
+            .with_context(|| format!("Failed to grab {path:?}"))?;
+    }
+    Ok(device)
+}
+
+/// Read all of the pending events from a non-blocking device.
+fn pending_events(device: &mut Device) -> io::Result<Vec<InputEvent>> {
+    let mut events = vec![];
+    loop {
+        match device.fetch_events() {
+            Ok(batch) => events.extend(batch),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(events),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Turns keyboard and mouse events into a controller's state.
+struct Translator {
+    buttons: Vec<(Button, u16)>,
+    /// The keys for the left stick, as (up, down, left, right).
+    left_stick: (u16, u16, u16, u16),
+    sensitivity: f32,
+    acceleration: f32,
+    invert_y: bool,
+    /// The keys and mouse buttons that are currently pressed.
+    pressed: HashSet<u16>,
+    /// Recent mouse movements, as (when, x, y).
+    movements: VecDeque<(Instant, i32, i32)>,
+    right_stick: Stick,
+}
+
+impl Translator {
+    fn new(config: &KeyboardMouseConfig) -> Result<Self> {
+        let mut buttons = DEFAULT_BUTTONS
+            .iter()
+            .filter(|(b, _)| !config.buttons.contains_key(b))
+            .map(|(b, k)| (*b, k.code()))
+            .collect::<Vec<_>>();
+        for (button, name) in &config.buttons {
+            buttons.push((*button, parse_key(name)?));
+        }
+        let stick = &config.left_stick;
+        let left_stick = (
+            parse_key(&stick.up)?,
+            parse_key(&stick.down)?,
+            parse_key(&stick.left)?,
+            parse_key(&stick.right)?,
+        );
+        Ok(Translator {
+            buttons,
+            left_stick,
+            sensitivity: config.sensitivity,
+            acceleration: config.acceleration,
+            invert_y: config.invert_y,
+            pressed: HashSet::new(),
+            movements: VecDeque::new(),
+            right_stick: Stick::default(),
+        })
+    }
+
+    /// Handle `events`, which arrived at `now`. Returns whether any inputs changed,
+    /// which includes the right stick settling after the mouse stops.
+    fn handle(&mut self, events: Vec<InputEvent>, now: Instant) -> bool {
+        let mut changed = false;
+        for event in events {
+            match event.kind() {
+                InputEventKind::Key(key) => {
+                    if event.value() == 0 {
+                        self.pressed.remove(&key.code());
+                    } else {
+                        self.pressed.insert(key.code());
+                    }
+                    changed = true;
+                }
+                InputEventKind::RelAxis(RelativeAxisType::REL_X) => {
+                    self.movements.push_back((now, event.value(), 0));
+                }
+                InputEventKind::RelAxis(RelativeAxisType::REL_Y) => {
+                    self.movements.push_back((now, 0, event.value()));
+                }
+                _ => {}
+            }
+        }
+        while self
+            .movements
+            .front()
+            .is_some_and(|(when, _, _)| now - *when > MOUSE_WINDOW)
+        {
+            self.movements.pop_front();
+        }
+        let (x, y) = self
+            .movements
+            .iter()
+            .fold((0, 0), |(x, y), (_, dx, dy)| (x + dx, y + dy));
+        let window = MOUSE_WINDOW.as_secs_f32();
+        // Mouse Y is positive going down, stick Y is positive going up.
+        let y_sign = if self.invert_y { 1.0 } else { -1.0 };
+        let right_stick = Stick {
+            x: self.stick_value(x as f32 / window),
+            y: y_sign * self.stick_value(y as f32 / window),
+        };
+        if right_stick != self.right_stick {
+            self.right_stick = right_stick;
+            changed = true;
+        }
+        changed
+    }
+
+    /// How far to push the right stick for a mouse speed in counts per second.
+    fn stick_value(&self, speed: f32) -> f32 {
+        let value = (speed.abs() * self.sensitivity / FULL_SPEED)
+            .powf(self.acceleration)
+            .min(1.0);
+        value.copysign(speed)
+    }
+
+    fn state(&self) -> ControllerState {
+        let mut state = ControllerState::default();
+        for (button, key) in &self.buttons {
+            if self.pressed.contains(key) {
+                state.set_pressed(*button, true);
+            }
+        }
+        let key_value = |key| {
+            if self.pressed.contains(&key) {
+                1.0
+            } else {
+                0.0
+            }
+        };
+        let (up, down, left, right) = self.left_stick;
+        let x = key_value(right) - key_value(left);
+        let y = key_value(up) - key_value(down);
+        // Keep diagonals on the edge of the stick's circle, where a real stick would be.
+        let scale = if x != 0.0 && y != 0.0 {
+            std::f32::consts::FRAC_1_SQRT_2
+        } else {
+            1.0
+        };
+        state.left_stick = Stick {
+            x: x * scale,
+            y: y * scale,
+        };
+        state.right_stick = self.right_stick;
+        state
+    }
+}
+
+/// A keyboard and mouse that act as a single controller.
+pub struct KeyboardMouse {
+    keyboard: Device,
+    mouse: Option<Device>,
+    pub name: String,
+    /// The keyboard's device node.
+    pub path: PathBuf,
+    /// The keyboard's SDL GUID, formatted like `controller_key`.
+    pub guid: String,
+    translator: Translator,
+}
+
+impl KeyboardMouse {
+    /// Open the keyboard and mouse described by `config`, and grab them if configured to.
+    pub fn open(config: &KeyboardMouseConfig) -> Result<Self> {
+        let mut translator = Translator::new(config)?;
+        let keyboard = open_device(&config.keyboard, config.grab)?;
+        let mouse = match &config.mouse {
+            Some(path) => Some(open_device(path, config.grab)?),
+            None => None,
+        };
+        for device in [Some(&keyboard), mouse.as_ref()].into_iter().flatten() {
+            let keys = device.get_key_state()?;
+            translator.pressed.extend(keys.iter().map(|k| k.code()));
+        }
+        Ok(KeyboardMouse {
+            name: config.name.clone(),
+            path: config.keyboard.clone(),
+            guid: InputDevice::new(config.keyboard.clone(), &keyboard).guid,
+            keyboard,
+            mouse,
+            translator,
+        })
+    }
+
+    /// Read any pending events from the keyboard and mouse. Returns whether any
+    /// inputs changed, which includes the right stick settling after the mouse stops.
+    ///
+    /// An error means one of the devices has gone away.
+    pub fn poll(&mut self) -> io::Result<bool> {
+        let mut events = pending_events(&mut self.keyboard)?;
+        if let Some(mouse) = &mut self.mouse {
+            events.extend(pending_events(mouse)?);
+        }
+        Ok(self.translator.handle(events, Instant::now()))
+    }
+
+    /// The current state of the keyboard and mouse, as a controller.
+    pub fn state(&self) -> ControllerState {
+        self.translator.state()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StickKeys;
+    use evdev::EventType;
+    use std::collections::HashMap;
+
+    fn translator(sensitivity: f32, acceleration: f32, invert_y: bool) -> Translator {
+        Translator::new(&KeyboardMouseConfig {
+            name: "Keyboard and mouse".to_owned(),
+            keyboard: PathBuf::from("/dev/input/event0"),
+            mouse: None,
+            grab: false,
+            buttons: HashMap::from([(Button::South, "KEY_LEFTCTRL".to_owned())]),
+            left_stick: StickKeys::default(),
+            sensitivity,
+            acceleration,
+            invert_y,
+        })
+        .unwrap()
+    }
+
+    fn key(key: Key, pressed: bool) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.code(), pressed as i32)
+    }
+
+    fn motion(x: i32, y: i32) -> Vec<InputEvent> {
+        vec![
+            InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, x),
+            InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, y),
+        ]
+    }
+
+    fn assert_stick(stick: Stick, x: f32, y: f32) {
+        assert!(
+            (stick.x - x).abs() < 1e-4 && (stick.y - y).abs() < 1e-4,
+            "{stick:?} isn't ({x}, {y})"
+        );
+    }
+
+    #[test]
+    fn buttons() {
+        let mut t = translator(1.0, 1.0, false);
+        let now = Instant::now();
+        assert!(t.handle(vec![key(Key::KEY_E, true), key(Key::BTN_LEFT, true)], now));
+        let state = t.state();
+        assert!(state.is_pressed(Button::RightTrigger));
+        assert!(state.is_pressed(Button::RightTrigger2));
+        assert!(!state.is_pressed(Button::South));
+
+        // Rebinding a button replaces its default binding.
+        t.handle(vec![key(Key::KEY_SPACE, true)], now);
+        assert!(!t.state().is_pressed(Button::South));
+        t.handle(vec![key(Key::KEY_LEFTCTRL, true)], now);
+        assert!(t.state().is_pressed(Button::South));
+
+        t.handle(
+            vec![
+                key(Key::KEY_E, false),
+                key(Key::BTN_LEFT, false),
+                key(Key::KEY_LEFTCTRL, false),
+            ],
+            now,
+        );
+        assert!(t.state().buttons.is_empty());
+    }
+
+    #[test]
+    fn left_stick() {
+        let mut t = translator(1.0, 1.0, false);
+        let now = Instant::now();
+        t.handle(vec![key(Key::KEY_W, true)], now);
+        assert_stick(t.state().left_stick, 0.0, 1.0);
+        // Diagonals stay on the edge of the circle.
+        t.handle(vec![key(Key::KEY_D, true)], now);
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert_stick(t.state().left_stick, half, half);
+        // Opposite keys cancel out.
+        t.handle(vec![key(Key::KEY_S, true)], now);
+        assert_stick(t.state().left_stick, 1.0, 0.0);
+        t.handle(vec![key(Key::KEY_W, false), key(Key::KEY_A, true)], now);
+        assert_stick(t.state().left_stick, 0.0, -1.0);
+        t.handle(
+            vec![
+                key(Key::KEY_A, false),
+                key(Key::KEY_S, false),
+                key(Key::KEY_D, false),
+            ],
+            now,
+        );
+        assert_stick(t.state().left_stick, 0.0, 0.0);
+    }
+
+    #[test]
+    fn right_stick() {
+        let mut t = translator(1.0, 1.0, false);
+        let start = Instant::now();
+        // 50 counts in the 50ms window is 1000 counts per second, half of full speed.
+        // Moving the mouse down pushes the stick down.
+        assert!(t.handle(motion(50, 25), start));
+        assert_stick(t.state().right_stick, 0.5, -0.25);
+        // Movements add up over the window.
+        t.handle(motion(-100, 0), start + Duration::from_millis(10));
+        assert_stick(t.state().right_stick, -0.5, -0.25);
+        // Fast movements are clamped to full deflection.
+        t.handle(motion(1000, -1000), start + Duration::from_millis(20));
+        assert_stick(t.state().right_stick, 1.0, 1.0);
+        // The stick goes back to the middle once the mouse has stopped for the window.
+        assert!(!t.handle(vec![], start + Duration::from_millis(40)));
+        assert!(t.handle(vec![], start + Duration::from_millis(71)));
+        assert_stick(t.state().right_stick, 0.0, 0.0);
+        assert!(!t.handle(vec![], start + Duration::from_millis(80)));
+    }
+
+    #[test]
+    fn right_stick_curve() {
+        let now = Instant::now();
+        let mut t = translator(1.5, 1.0, false);
+        t.handle(motion(50, 0), now);
+        assert_stick(t.state().right_stick, 0.75, 0.0);
+
+        // With acceleration, slow movements move the stick less.
+        let mut t = translator(1.0, 2.0, false);
+        t.handle(motion(50, -50), now);
+        assert_stick(t.state().right_stick, 0.25, 0.25);
+
+        let mut t = translator(1.0, 1.0, true);
+        t.handle(motion(0, 50), now);
+        assert_stick(t.state().right_stick, 0.0, 0.5);
+    }
+}
@@ -0,0 +1,334 @@
+use log::{debug, info};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where to look for the hidraw devices the host creates for the gadget devices.
+const HIDRAW_CLASS: &str = "/sys/class/hidraw";
+/// How often to look for new hidraw devices.
+const SCAN_INTERVAL: Duration = Duration::from_secs(2);
+/// How often to log the latencies measured so far.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+/// The most reports per gadget device that can be waiting for the host to read them.
+/// Reports the host never reads (like when nothing is reading on this machine)
+/// would otherwise pile up forever.
+const MAX_IN_FLIGHT: usize = 64;
+/// How many of the latest latencies each histogram keeps percentiles of, so that
+/// a daemon that runs for a long time doesn't keep using more memory.
+const MAX_SAMPLES: usize = 10_000;
+
+/// The latest latencies in microseconds, from which percentiles can be taken.
+#[derive(Default)]
+pub struct Histogram {
+    /// Once this is full, each new sample replaces the oldest one.
+    samples: Vec<u32>,
+    /// Where the next sample goes once `samples` is full.
+    next: usize,
+    /// How many samples have ever been recorded.
+    total: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let sample = latency.as_micros().min(u32::MAX as u128) as u32;
+        if self.samples.len() < MAX_SAMPLES {
+            self.samples.push(sample);
+        } else {
+            self.samples[self.next] = sample;
+            self.next = (self.next + 1) % MAX_SAMPLES;
+        }
+        self.total += 1;
+    }
+
+    /// How many samples percentiles are taken from.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// The latency that `p` percent of the samples are no longer than.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        percentile(&sorted, p)
+    }
+
+    /// A one-line summary, like `n=120 p50=1.2ms p95=2.0ms p99=3.1ms max=4.0ms`.
+    /// `n` counts every sample, but the percentiles are of the latest `MAX_SAMPLES`.
+    pub fn summary(&self) -> String {
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable();
+        let ms = |p: f64| percentile(&sorted, p).as_secs_f64() * 1000.0;
+        format!(
+            "n={} p50={:.2}ms p95={:.2}ms p99={:.2}ms max={:.2}ms",
+            self.total,
+            ms(50.0),
+            ms(95.0),
+            ms(99.0),
+            ms(100.0),
+        )
+    }
+}
+
+/// The latency that `p` percent of the `sorted` samples are no longer than.
+fn percentile(sorted: &[u32], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    let i = rank.clamp(1, sorted.len()) - 1;
+    Duration::from_micros(sorted[i] as u64)
+}
+
+/// A report read on the host's side of a gadget device.
+enum HostRead {
+    Report {
+        slot: usize,
+        report: Vec<u8>,
+        time: Instant,
+    },
+    /// The hidraw device went away, so it needs to be found again.
+    Closed(PathBuf),
+}
+
+/// The gadget device index that a hidraw device belongs to, from its `HID_PHYS`.
+///
+/// USB devices end with the interface number (like `usb-dummy_hcd.0-1/input2`),
+/// and the gadget's HID functions are its interfaces in order. uhid devices end
+/// with the name they were created with (like `pizero-gadget-gamepads/uhid2`).
+fn slot_from_phys(phys: &str) -> Option<usize> {
+    let (_, last) = phys.rsplit_once('/')?;
+    last.strip_prefix("input")
+        .or_else(|| last.strip_prefix("uhid"))?
+        .parse()
+        .ok()
+}
+
+/// The hidraw devices on this machine for devices with these USB IDs, with the gadget
+/// device index each one belongs to.
+fn find_hidraw_devices(vendor_id: u32, product_id: u32) -> Vec<(PathBuf, usize)> {
+    let Ok(entries) = fs::read_dir(HIDRAW_CLASS) else {
+        return vec![];
+    };
+    let hid_id = format!("HID_ID=0003:{vendor_id:08X}:{product_id:08X}");
+    let mut devices = vec![];
+    for entry in entries.flatten() {
+        let Ok(uevent) = fs::read_to_string(entry.path().join("device/uevent")) else {
+            continue;
+        };
+        if !uevent.lines().any(|line| line == hid_id) {
+            continue;
+        }
+        let slot = uevent
+            .lines()
+            .find_map(|line| line.strip_prefix("HID_PHYS="))
+            .and_then(slot_from_phys);
+        if let Some(slot) = slot {
+            devices.push((Path::new("/dev").join(entry.file_name()), slot));
+        }
+    }
+    devices
+}
+
+/// Read reports from a hidraw device until it goes away.
+fn read_host_reports(mut file: File, path: PathBuf, slot: usize, reads: Sender<HostRead>) {
+    let mut buf = [0; 4096];
+    loop {
+        match file.read(&mut buf) {
+            Ok(len) if len > 0 => {
+                let read = HostRead::Report {
+                    slot,
+                    report: buf[..len].to_vec(),
+                    time: Instant::now(),
+                };
+                if reads.send(read).is_err() {
+                    return;
+                }
+            }
+            Ok(_) => break,
+            Err(e) => {
+                debug!("Stopped reading {path:?}: {e}");
+                break;
+            }
+        }
+    }
+    let _ = reads.send(HostRead::Closed(path));
+}
+
+/// Measures how long it takes for input to come out of the gadget devices.
+///
+/// Each change is timed from when the input event that caused it arrived to when its
+/// report was written to the gadget device, and, when the host is this machine (like
+/// with dummy_hcd or uhid), to when the host read it from the matching hidraw device.
+pub struct LatencyTracker {
+    vendor_id: u32,
+    product_id: u32,
+    /// When the input that the next report for each gadget device will carry arrived.
+    changed: HashMap<usize, Instant>,
+    /// Event to gadget device write, by gadget device.
+    written: BTreeMap<usize, Histogram>,
+    /// Event to host read, by gadget device.
+    read: BTreeMap<usize, Histogram>,
+    /// Reports written but not read by the host yet, oldest first, with when the
+    /// input that caused them arrived.
+    in_flight: HashMap<usize, VecDeque<(Vec<u8>, Instant)>>,
+    /// The hidraw devices being read.
+    host_devices: HashSet<PathBuf>,
+    reads: Receiver<HostRead>,
+    reads_sender: Sender<HostRead>,
+    last_scan: Option<Instant>,
+    last_summary: Instant,
+}
+
+impl LatencyTracker {
+    /// Track the latency of a gamepad with these USB IDs.
+    pub fn new(vendor_id: u32, product_id: u32) -> Self {
+        let (reads_sender, reads) = mpsc::channel();
+        LatencyTracker {
+            vendor_id,
+            product_id,
+            changed: HashMap::new(),
+            written: BTreeMap::new(),
+            read: BTreeMap::new(),
+            in_flight: HashMap::new(),
+            host_devices: HashSet::new(),
+            reads,
+            reads_sender,
+            last_scan: None,
+            last_summary: Instant::now(),
+        }
+    }
+
+    /// Note that input which arrived at `arrival` changed the report for `slot`.
+    /// If an earlier change hasn't been written yet, that one is kept, since the
+    /// next report carries both.
+    pub fn changed(&mut self, slot: usize, arrival: Instant) {
+        self.changed.entry(slot).or_insert(arrival);
+    }
+
+    /// Forget the change to `slot`'s report, because it turned out not to change
+    /// anything that needed sending.
+    pub fn discard(&mut self, slot: usize) {
+        self.changed.remove(&slot);
+    }
+
+    /// Note that `report` has just been written to `slot`.
+    pub fn written(&mut self, slot: usize, report: &[u8]) {
+        let Some(arrival) = self.changed.remove(&slot) else {
+            return;
+        };
+        self.written
+            .entry(slot)
+            .or_default()
+            .record(arrival.elapsed());
+        if self.host_devices.is_empty() {
+            return;
+        }
+        let in_flight = self.in_flight.entry(slot).or_default();
+        if in_flight.len() >= MAX_IN_FLIGHT {
+            in_flight.pop_front();
+        }
+        in_flight.push_back((report.to_vec(), arrival));
+    }
+
+    /// Match up the reports the host has read, look for new hidraw devices, and log
+    /// the latencies every so often.
+    pub fn poll(&mut self) {
+        while let Ok(read) = self.reads.try_recv() {
+            match read {
+                HostRead::Report { slot, report, time } => self.host_read(slot, &report, time),
+                HostRead::Closed(path) => {
+                    self.host_devices.remove(&path);
+                }
+            }
+        }
+        if self.last_scan.is_none_or(|t| t.elapsed() >= SCAN_INTERVAL) {
+            self.scan();
+            self.last_scan = Some(Instant::now());
+        }
+        if self.last_summary.elapsed() >= SUMMARY_INTERVAL {
+            self.log_summary();
+            self.last_summary = Instant::now();
+        }
+    }
+
+    fn host_read(&mut self, slot: usize, report: &[u8], time: Instant) {
+        let Some(in_flight) = self.in_flight.get_mut(&slot) else {
+            return;
+        };
+        // Reports are read in the order they were written, so anything before the
+        // match was either not caused by input or was lost.
+        let Some(i) = in_flight.iter().position(|(r, _)| r == report) else {
+            return;
+        };
+        let arrival = in_flight[i].1;
+        in_flight.drain(..=i);
+        self.read
+            .entry(slot)
+            .or_default()
+            .record(time.saturating_duration_since(arrival));
+    }
+
+    fn scan(&mut self) {
+        for (path, slot) in find_hidraw_devices(self.vendor_id, self.product_id) {
+            if self.host_devices.contains(&path) {
+                continue;
+            }
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    debug!("Failed to open {path:?}: {e}");
+                    continue;
+                }
+            };
+            info!("Reading gadget device {slot} from the host's side at {path:?}");
+            self.host_devices.insert(path.clone());
+            let reads = self.reads_sender.clone();
+            thread::spawn(move || read_host_reports(file, path, slot, reads));
+        }
+    }
+
+    /// Log the percentiles of the latest latencies.
+    pub fn log_summary(&self) {
+        if self.written.is_empty() {
+            info!("Latency: no input yet");
+        }
+        for (slot, histogram) in &self.written {
+            info!("Latency {slot}: event to write {}", histogram.summary());
+            if let Some(histogram) = self.read.get(slot) {
+                info!("Latency {slot}: event to host read {}", histogram.summary());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_keeps_the_latest_samples() {
+        let mut histogram = Histogram::default();
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(histogram.percentile(100.0), Duration::from_millis(100));
+
+        for _ in 0..MAX_SAMPLES {
+            histogram.record(Duration::from_micros(10));
+        }
+        assert_eq!(histogram.len(), MAX_SAMPLES);
+        assert_eq!(histogram.percentile(100.0), Duration::from_micros(10));
+        assert!(histogram
+            .summary()
+            .starts_with(&format!("n={} ", MAX_SAMPLES + 100)));
+    }
+}
@@ -0,0 +1,432 @@
+use anyhow::{anyhow, bail, Context, Result};
+use evdev::{Device, InputEvent, InputEventKind};
+use gilrs::{Event, EventType, GamepadId, Gilrs, GilrsBuilder};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::control::{send_request, Request, Response};
+use crate::evdev_input::{set_nonblocking, AbsState};
+use crate::input_device::{InputDevice, EV_ABS, EV_KEY};
+use crate::slot_affinity::controller_key;
+
+/// How long to wait for an input before skipping it.
+const SKIP_AFTER: Duration = Duration::from_secs(8);
+/// How far an axis has to move from where it rests to count as an input.
+const AXIS_THRESHOLD: f32 = 0.75;
+/// How close to where it rests an axis has to get to count as released.
+const AXIS_RELEASED: f32 = 0.25;
+/// How long the axes are watched for to find where they rest.
+const REST_SAMPLE_TIME: Duration = Duration::from_secs(1);
+/// How often the axes are sampled while finding where they rest.
+const REST_SAMPLE_INTERVAL: Duration = Duration::from_millis(20);
+
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+
+/// The kind of input we're asking the user for.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    /// A button, or a d-pad hat direction.
+    Button,
+    /// A stick axis.
+    Axis,
+    /// An analog trigger, or a digital one.
+    Trigger,
+}
+
+/// The inputs to learn, as (SDL name, prompt, kind).
+const INPUTS: &[(&str, &str, Kind)] = &[
+    ("a", "Press the bottom face button (Xbox A)", Kind::Button),
+    ("b", "Press the right face button (Xbox B)", Kind::Button),
+    ("x", "Press the left face button (Xbox X)", Kind::Button),
+    ("y", "Press the top face button (Xbox Y)", Kind::Button),
+    ("back", "Press Back/Select/-", Kind::Button),
+    ("start", "Press Start/+", Kind::Button),
+    ("guide", "Press the Guide/Home button", Kind::Button),
+    (
+        "leftshoulder",
+        "Press the left shoulder button (LB/L)",
+        Kind::Button,
+    ),
+    (
+        "rightshoulder",
+        "Press the right shoulder button (RB/R)",
+        Kind::Button,
+    ),
+    (
+        "lefttrigger",
+        "Pull the left trigger (LT/ZL)",
+        Kind::Trigger,
+    ),
+    (
+        "righttrigger",
+        "Pull the right trigger (RT/ZR)",
+        Kind::Trigger,
+    ),
+    ("leftstick", "Click the left stick", Kind::Button),
+    ("rightstick", "Click the right stick", Kind::Button),
+    ("dpup", "Press d-pad up", Kind::Button),
+    ("dpdown", "Press d-pad down", Kind::Button),
+    ("dpleft", "Press d-pad left", Kind::Button),
+    ("dpright", "Press d-pad right", Kind::Button),
+    ("leftx", "Move the left stick right", Kind::Axis),
+    ("lefty", "Move the left stick down", Kind::Axis),
+    ("rightx", "Move the right stick right", Kind::Axis),
+    ("righty", "Move the right stick down", Kind::Axis),
+];
+
+/// The SDL hat direction for a d-pad hat axis moving in the direction of `moved`.
+fn hat_binding(code: u16, moved: f32) -> Option<&'static str> {
+    match (code, moved > 0.0) {
+        (ABS_HAT0Y, false) => Some("h0.1"),
+        (ABS_HAT0X, true) => Some("h0.2"),
+        (ABS_HAT0Y, true) => Some("h0.4"),
+        (ABS_HAT0X, false) => Some("h0.8"),
+        _ => None,
+    }
+}
+
+/// The SDL binding for axis `axis` (like `a2`) moving `moved` away from `rest`,
+/// when asked for an input of `kind`. Returns an error saying why the movement
+/// doesn't fit if it doesn't.
+fn axis_binding(axis: &str, kind: Kind, rest: f32, moved: f32) -> Result<String, &'static str> {
+    let centered = rest.abs() < AXIS_RELEASED;
+    Ok(match kind {
+        // Only half of the axis is the button.
+        Kind::Button if moved > 0.0 => format!("+{axis}"),
+        Kind::Button => format!("-{axis}"),
+        // Sticks rest in the middle, and are prompted for in their positive direction,
+        // so moving the other way means the axis is inverted.
+        Kind::Axis if !centered => return Err("that doesn't rest in the middle like a stick"),
+        Kind::Axis if moved > 0.0 => axis.to_owned(),
+        Kind::Axis => format!("{axis}~"),
+        // A trigger that rests in the middle of its axis only uses one half of it.
+        Kind::Trigger if centered && moved > 0.0 => format!("+{axis}"),
+        Kind::Trigger if centered => format!("-{axis}"),
+        // Otherwise it uses all of it, starting from whichever end it rests at.
+        Kind::Trigger if moved > 0.0 => axis.to_owned(),
+        Kind::Trigger => format!("{axis}~"),
+    })
+}
+
+/// Interactively builds an SDL mapping for a single gamepad, reading its evdev
+/// device directly so we see raw codes and values.
+struct Learner {
+    device: Device,
+    info: InputDevice,
+    /// Events read from the device that haven't been looked at yet.
+    pending: VecDeque<InputEvent>,
+    /// The range and current value of each axis, by code.
+    abs: HashMap<u16, AbsState>,
+    /// Where each axis rests when it's left alone, from -1.0 to 1.0.
+    rest: HashMap<u16, f32>,
+    /// The inputs that have already been learned, so they aren't picked twice.
+    used: Vec<String>,
+}
+
+impl Learner {
+    fn open(info: InputDevice) -> Result<Self> {
+        let path = &info.path;
+        let device =
+            Device::open(path).with_context(|| format!("Failed to open evdev device: {path:?}"))?;
+        set_nonblocking(&device).with_context(|| format!("Failed to set up {path:?}"))?;
+        let abs_state = device.get_abs_state()?;
+        let abs = info
+            .axes
+            .iter()
+            .map(|code| {
+                let info = abs_state[*code as usize];
+                let state = AbsState {
+                    value: info.value,
+                    min: info.minimum,
+                    max: info.maximum,
+                };
+                (*code, state)
+            })
+            .collect();
+        Ok(Learner {
+            device,
+            info,
+            pending: VecDeque::new(),
+            abs,
+            rest: HashMap::new(),
+            used: vec![],
+        })
+    }
+
+    /// Wait for the next button or axis event from the device, up to `deadline`.
+    fn next_event(&mut self, deadline: Instant) -> Result<Option<InputEvent>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                if let InputEventKind::AbsAxis(axis) = event.kind() {
+                    if let Some(state) = self.abs.get_mut(&axis.0) {
+                        state.value = event.value();
+                    }
+                }
+                match event.kind() {
+                    InputEventKind::Key(_) | InputEventKind::AbsAxis(_) => return Ok(Some(event)),
+                    _ => continue,
+                }
+            }
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                return Ok(None);
+            };
+            let mut fds = [libc::pollfd {
+                fd: self.device.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            // SAFETY: `fds` is a valid array of one pollfd.
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout.as_millis() as i32) };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            match self.device.fetch_events() {
+                Ok(events) => self.pending.extend(events),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Failed to read from the controller"),
+            }
+        }
+    }
+
+    /// Read events until `deadline`, ignoring them.
+    fn skip_events(&mut self, deadline: Instant) -> Result<()> {
+        while self.next_event(deadline)?.is_some() {}
+        Ok(())
+    }
+
+    fn value(&self, code: u16) -> f32 {
+        self.abs.get(&code).map(AbsState::normalized).unwrap_or(0.0)
+    }
+
+    /// Measure where each axis rests by watching them while the controller is left
+    /// alone, starting again if anything moves.
+    fn sample_rest(&mut self) -> Result<()> {
+        println!("Leave the controller alone while its axes are measured");
+        'sample: loop {
+            let start = self
+                .abs
+                .keys()
+                .map(|code| (*code, self.value(*code)))
+                .collect::<HashMap<_, _>>();
+            let mut sums = start.clone();
+            let mut samples = 1;
+            let deadline = Instant::now() + REST_SAMPLE_TIME;
+            while Instant::now() < deadline {
+                self.skip_events(Instant::now() + REST_SAMPLE_INTERVAL)?;
+                for (code, sum) in &mut sums {
+                    let value = self.value(*code);
+                    if (value - start[code]).abs() >= AXIS_RELEASED {
+                        println!("  Something moved, measuring again");
+                        continue 'sample;
+                    }
+                    *sum += value;
+                }
+                samples += 1;
+            }
+            self.rest = sums
+                .into_iter()
+                .map(|(code, sum)| (code, sum / samples as f32))
+                .collect();
+            return Ok(());
+        }
+    }
+
+    /// Wait for an input of the given kind, returning its SDL binding and the event
+    /// type and code it came from.
+    fn capture(&mut self, kind: Kind) -> Result<Option<(String, (u16, u16))>> {
+        let deadline = Instant::now() + SKIP_AFTER;
+        loop {
+            let Some(event) = self.next_event(deadline)? else {
+                return Ok(None);
+            };
+            let code = event.code();
+            let binding = match event.kind() {
+                InputEventKind::Key(_) if kind != Kind::Axis && event.value() == 1 => {
+                    self.info.binding(EV_KEY, code)
+                }
+                InputEventKind::AbsAxis(_) => {
+                    let Some(rest) = self.rest.get(&code).copied() else {
+                        continue;
+                    };
+                    let moved = self.value(code) - rest;
+                    if moved.abs() < AXIS_THRESHOLD {
+                        continue;
+                    }
+                    let Some(axis) = self.info.binding(EV_ABS, code) else {
+                        continue;
+                    };
+                    match hat_binding(code, moved) {
+                        Some(hat) if kind == Kind::Button => Some(hat.to_owned()),
+                        Some(_) => None,
+                        None => match axis_binding(&axis, kind, rest, moved) {
+                            Ok(binding) => Some(binding),
+                            Err(reason) => {
+                                println!("  {axis} moved, but {reason}, try again");
+                                None
+                            }
+                        },
+                    }
+                }
+                _ => continue,
+            };
+            let Some(binding) = binding else {
+                continue;
+            };
+            // An inverted axis is still the same axis.
+            if self
+                .used
+                .contains(&binding.trim_end_matches('~').to_owned())
+            {
+                println!("  {binding} is already mapped, try again");
+                continue;
+            }
+            return Ok(Some((binding, (event.event_type().0, code))));
+        }
+    }
+
+    /// Wait for an input to be released, so holding it doesn't count as the next input.
+    fn wait_for_release(&mut self, (ev_type, code): (u16, u16)) -> Result<()> {
+        let deadline = Instant::now() + SKIP_AFTER;
+        while let Some(event) = self.next_event(deadline)? {
+            if event.event_type().0 != ev_type || event.code() != code {
+                continue;
+            }
+            let released = match ev_type {
+                EV_KEY => event.value() == 0,
+                _ => (self.value(code) - self.rest[&code]).abs() < AXIS_RELEASED,
+            };
+            if released {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wait for a button press on any gamepad, and return that gamepad.
+fn choose_gamepad(gilrs: &mut Gilrs) -> GamepadId {
+    println!("Press a button on the controller you want to map");
+    loop {
+        if let Some(Event {
+            id,
+            event: EventType::ButtonPressed(..),
+            ..
+        }) = gilrs.next_event_blocking(None)
+        {
+            return id;
+        }
+    }
+}
+
+/// Replace any mapping for `guid` in the mapping file at `path` with `line`.
+fn save_mapping(path: &Path, guid: &str, line: &str) -> Result<()> {
+    let contents = if path.exists() {
+        fs::read_to_string(path)
+            .with_context(|| format!("Failed to read mapping file: {path:?}"))?
+    } else {
+        String::new()
+    };
+    let mut lines = contents
+        .lines()
+        .filter(|l| {
+            !l.split_once(',')
+                .is_some_and(|(g, _)| g.eq_ignore_ascii_case(guid))
+        })
+        .map(|l| format!("{l}\n"))
+        .collect::<String>();
+    lines.push_str(line);
+    lines.push('\n');
+    fs::File::create(path)
+        .and_then(|mut f| f.write_all(lines.as_bytes()))
+        .with_context(|| format!("Failed to write mapping file: {path:?}"))
+}
+
+/// Interactively learn an SDL mapping for a gamepad, save it to the configured
+/// mapping file and tell the daemon to start using it.
+pub fn learn(config: &Config) -> Result<()> {
+    // Skip all of the mappings and filters so we see raw codes from the device.
+    let mut gilrs = GilrsBuilder::new()
+        .with_default_filters(false)
+        .add_included_mappings(false)
+        .add_env_mappings(false)
+        .build()
+        .map_err(|e| anyhow!("Error initializing gilrs: {e}"))?;
+    let id = choose_gamepad(&mut gilrs);
+    let gamepad = gilrs.gamepad(id);
+    let guid = controller_key(gamepad.uuid());
+    let name = gamepad.os_name().to_owned();
+    let info = InputDevice::find(&guid, &name)
+        .ok_or_else(|| anyhow!("Couldn't find the input device for {name}"))?;
+    println!("Found {name} at {}", info.path.display());
+    let mut learner = Learner::open(info)?;
+    drop(gilrs);
+    // Let go of the button that picked the gamepad.
+    learner.skip_events(Instant::now() + Duration::from_millis(500))?;
+    learner.sample_rest()?;
+
+    println!(
+        "Mapping {name} ({guid}). Leave an input alone for {} seconds to skip it.",
+        SKIP_AFTER.as_secs()
+    );
+    let mut fields = String::new();
+    for (sdl_name, prompt, kind) in INPUTS {
+        println!("{prompt}");
+        match learner.capture(*kind)? {
+            Some((binding, code)) => {
+                println!("  {sdl_name}:{binding}");
+                fields.push_str(&format!("{sdl_name}:{binding},"));
+                learner.used.push(binding.trim_end_matches('~').to_owned());
+                learner.wait_for_release(code)?;
+            }
+            None => println!("  Skipped"),
+        }
+    }
+    if fields.is_empty() {
+        bail!("Nothing was mapped");
+    }
+    let line = learner.info.sdl_mapping(&fields);
+    println!("\n{line}\n");
+
+    let Some(path) = &config.mapping_file else {
+        println!("Set mapping_file in the config to save learned mappings");
+        return Ok(());
+    };
+    save_mapping(path, &guid, &line)?;
+    println!("Saved mapping to {}", path.display());
+    if let Some(socket) = config.control_socket() {
+        match send_request(socket, &Request::Reload) {
+            Ok(Response::Error { message }) => bail!("Daemon failed to reload: {message}"),
+            Ok(_) => println!("Daemon reloaded"),
+            Err(_) => println!("Daemon isn't running, it will use the mapping next time it starts"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_bindings() {
+        assert_eq!(axis_binding("a0", Kind::Axis, 0.0, 1.0).unwrap(), "a0");
+        assert_eq!(axis_binding("a1", Kind::Axis, 0.02, -0.9).unwrap(), "a1~");
+        assert!(axis_binding("a2", Kind::Axis, -1.0, 2.0).is_err());
+        assert_eq!(axis_binding("a2", Kind::Trigger, -1.0, 2.0).unwrap(), "a2");
+        assert_eq!(axis_binding("a2", Kind::Trigger, 1.0, -2.0).unwrap(), "a2~");
+        assert_eq!(axis_binding("a5", Kind::Trigger, 0.0, 1.0).unwrap(), "+a5");
+        assert_eq!(axis_binding("a5", Kind::Trigger, 0.0, -1.0).unwrap(), "-a5");
+        assert_eq!(axis_binding("a3", Kind::Button, 0.0, -1.0).unwrap(), "-a3");
+        assert_eq!(hat_binding(ABS_HAT0Y, -1.0), Some("h0.1"));
+    }
+}
@@ -1,16 +1,32 @@
-use gilrs::{ev::Code, Axis, Button, Gamepad};
 use scroll::{
     ctx::{SizeWith, TryIntoCtx},
     Endian, LE,
 };
-use std::{
-    collections::HashMap,
-    fmt::{Debug, Display},
-};
+use std::fmt::{Debug, Display};
+
+use crate::controller_state::ControllerState;
 
+pub mod config;
+pub mod control;
+pub mod controller_state;
+pub mod dsu_input;
+pub mod evdev_input;
 pub mod gamecube_adapter;
 pub mod hid_gadget;
 pub mod hori_pokken;
+pub mod host_emulator;
+pub mod input_device;
+pub mod keyboard_mouse;
+pub mod latency;
+pub mod learn;
+pub mod mappings;
+pub mod network_input;
+pub mod playback;
+pub mod raw_mapping;
+pub mod recorder;
+pub mod slot_affinity;
+pub mod udc;
+pub mod uhid;
 
 pub trait HIDGamepad: Debug {
     /// The HID descriptor for this gamepad
@@ -23,17 +39,33 @@ pub trait HIDGamepad: Debug {
     const VENDOR_ID: &'static [u8; 6];
     /// The USB Product ID, in hex with a 0x prefix
     const PRODUCT_ID: &'static [u8; 6];
+    /// USB device serial number
+    const SERIAL_NUMBER: &'static str = "0";
+    /// The device release number (bcdDevice), in hex with a 0x prefix
+    const BCD_DEVICE: &'static [u8; 6] = b"0x0100"; // v1.0.0
+    /// The USB version (bcdUSB), in hex with a 0x prefix
+    const BCD_USB: &'static [u8; 6] = b"0x0200"; // USB 2.0
+    /// The most power the device draws from the bus, in mA
+    const MAX_POWER: u16 = 250;
     /// Does this gamepad have analog buttons (including triggers)
     const ANALOG_BUTTONS: bool;
     /// The format of the HID report to send
-    type Report: SizeWith<Endian> + TryIntoCtx<Endian, Error = scroll::Error> + Display;
+    ///
+    /// The default value must be a report with no inputs active.
+    type Report: SizeWith<Endian> + TryIntoCtx<Endian, Error = scroll::Error> + Display + Default;
 
     fn report_size() -> usize {
         <Self::Report as SizeWith<Endian>>::size_with(&LE)
     }
-    fn fill_report(
-        gamepad: &Gamepad,
-        button_mapping: &HashMap<Button, Code>,
-        axis_mapping: &HashMap<Axis, Code>,
-    ) -> Self::Report;
+    /// Build a report from the state of a controller's inputs.
+    ///
+    /// Inputs that this gamepad doesn't have are ignored.
+    fn fill_report(state: &ControllerState) -> Self::Report;
+    /// Handle an output report from the host, returning any input reports to send in reply.
+    ///
+    /// This is for gamepads that have to answer the host, like with a handshake.
+    /// By default output reports are ignored.
+    fn handle_output_report(_report: &[u8]) -> Vec<Vec<u8>> {
+        vec![]
+    }
 }
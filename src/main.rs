@@ -1,164 +1,1727 @@
 use anyhow::{anyhow, bail, Result};
 use env_logger::Builder;
-use gilrs::{ev::Code, Button, Event, EventType, Gamepad, GamepadId, GilrsBuilder};
 use gilrs::{Axis, MappingSource};
-use log::{debug, error, info, log_enabled, Level, LevelFilter};
+use gilrs::{Button, Event, EventType, GamepadId, Gilrs, GilrsBuilder};
+use log::{debug, error, info, log_enabled, warn, Level, LevelFilter};
 use signal_hook::consts::signal::*;
 use signal_hook::flag as signal_flag;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fmt::Write;
+use std::hash::Hash;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use pizero_gadget_gamepads::config::{Config, Profile, DEFAULT_CONFIG_PATH};
+use pizero_gadget_gamepads::control::{
+    ControlServer, ControllerId, ControllerInfo, DaemonStatus, Request, Response, SlotInfo,
+    SlotStatus,
+};
+use pizero_gadget_gamepads::controller_state::{ControllerState, AXES, BUTTONS};
+use pizero_gadget_gamepads::dsu_input::DsuInput;
+use pizero_gadget_gamepads::evdev_input::EvdevController;
 use pizero_gadget_gamepads::hid_gadget::*;
 use pizero_gadget_gamepads::hori_pokken::*;
+use pizero_gadget_gamepads::host_emulator::{HostEmulator, Script};
+use pizero_gadget_gamepads::input_device::InputDevice;
+use pizero_gadget_gamepads::keyboard_mouse::KeyboardMouse;
+use pizero_gadget_gamepads::latency::LatencyTracker;
+use pizero_gadget_gamepads::learn::learn;
+use pizero_gadget_gamepads::mappings::Mappings;
+use pizero_gadget_gamepads::network_input::{NetworkEvent, NetworkInput};
+use pizero_gadget_gamepads::playback::{Frame, Playback};
+use pizero_gadget_gamepads::recorder::Recorder;
+use pizero_gadget_gamepads::slot_affinity::{controller_key, SlotAffinity};
+use pizero_gadget_gamepads::udc::{UdcState, UdcWatcher};
+use pizero_gadget_gamepads::uhid;
 use pizero_gadget_gamepads::HIDGamepad;
 
 struct RealGamepadToGadgetMapping<G: HIDGamepad> {
-    /// The mapping of standardized buttons to event codes for this device.
-    button_map: HashMap<Button, Code>,
-    /// The mapping of standardized axes to event codes for this device.
-    axis_map: HashMap<Axis, Code>,
+    /// Map of gadget button => the controller button that drives it.
+    button_map: HashMap<Button, Button>,
+    /// Map of gadget axis => the controller axis that drives it.
+    axis_map: HashMap<Axis, Axis>,
     /// The gadget device to which we're routing its data.
     gadget_file: HIDGadgetDeviceFile<G>,
 }
 
-fn try_map_gamepad<G: HIDGamepad>(
-    gamepad: &Gamepad,
-    gadget: &mut HIDGadget<G>,
-    mappings: &mut HashMap<GamepadId, RealGamepadToGadgetMapping<G>>,
-) -> Result<()> {
-    info!("Gamepad connected: {}", gamepad.name());
-    if gamepad.mapping_source() != MappingSource::SdlMappings {
-        bail!("Not using gamepad {}, no mapping data", gamepad.name());
-    }
-    let gadget_file = gadget.take_device()?;
-    let mut button_map = HashMap::new();
-    use Button::*;
-    for button in [
-        South,
-        East,
-        North,
-        West,
-        // Intentionally skipping C,
-        // Intentionally skipping Z,
-        LeftTrigger,
-        LeftTrigger2,
-        RightTrigger,
-        RightTrigger2,
-        Select,
-        Start,
-        Mode,
-        LeftThumb,
-        RightThumb,
-        DPadUp,
-        DPadDown,
-        DPadLeft,
-        DPadRight,
-    ] {
-        if let Some(code) = gamepad.button_code(button) {
-            button_map.insert(button, code);
-        }
-    }
-    let mut axis_map = HashMap::new();
-    use Axis::*;
-    for axis in [LeftStickX, LeftStickY, RightStickX, RightStickY] {
-        if let Some(code) = gamepad.axis_code(axis) {
-            axis_map.insert(axis, code);
-        }
-    }
-    info!("Mapping {} to {}", gamepad.name(), gadget_file.path());
+/// Where a controller's input comes from.
+enum Source {
+    Gilrs(GamepadId),
+    Evdev(Box<EvdevController>),
+    KeyboardMouse(Box<KeyboardMouse>),
+    Network(Box<NetworkInput>),
+    Dsu(Box<DsuInput>),
+}
+
+/// A connected controller, which may or may not be mapped to gadget devices.
+struct Controller<G: HIDGamepad> {
+    name: String,
+    /// The key used to identify this controller in the slot affinity table.
+    key: String,
+    source: Source,
+    /// One entry per gadget device this controller drives. Empty if it isn't mapped.
+    mappings: Vec<RealGamepadToGadgetMapping<G>>,
+}
+
+fn log_mapping(button_map: &HashMap<Button, Button>, axis_map: &HashMap<Axis, Axis>) {
     if log_enabled!(Level::Debug) {
         let mut s = "  Axes:\n".to_owned();
-        for (a, c) in axis_map.iter() {
-            drop(writeln!(&mut s, "    {c} => {a:?}"));
+        for (to, from) in axis_map.iter() {
+            let _ = writeln!(&mut s, "    {from:?} => {to:?}");
         }
-        drop(writeln!(&mut s, "  Buttons:"));
-        for (b, c) in button_map.iter() {
-            drop(writeln!(&mut s, "    {c} => {b:?}"));
+        let _ = writeln!(&mut s, "  Buttons:");
+        for (to, from) in button_map.iter() {
+            let _ = writeln!(&mut s, "    {from:?} => {to:?}");
         }
         debug!("Mapping:\n{s}");
     }
-    mappings.insert(
-        gamepad.id(),
-        RealGamepadToGadgetMapping {
-            button_map,
-            axis_map,
-            gadget_file,
-        },
-    );
-    Ok(())
 }
 
-fn update_gamepad<G: HIDGamepad>(
-    gamepad: &Gamepad,
-    mapping: &mut RealGamepadToGadgetMapping<G>,
-) -> Result<()> {
-    let report = G::fill_report(gamepad, &mapping.button_map, &mapping.axis_map);
-    if log_enabled!(Level::Debug) {
-        debug!("{}: {report}", gamepad.name());
+/// Build a map containing only the entries in `selection`, which maps new keys to keys in `map`.
+fn select<K: Copy + Eq + Hash>(map: &HashMap<K, K>, selection: &HashMap<K, K>) -> HashMap<K, K> {
+    selection
+        .iter()
+        .filter_map(|(to, from)| map.get(from).map(|c| (*to, *c)))
+        .collect()
+}
+
+/// Build a copy of `map` with the entries in `remap`, which maps new keys to keys in `map`,
+/// replacing the originals.
+fn remap<K: Copy + Eq + Hash>(map: &HashMap<K, K>, remap: &HashMap<K, K>) -> HashMap<K, K> {
+    let mut out = map.clone();
+    for (to, from) in remap {
+        match map.get(from) {
+            Some(c) => out.insert(*to, *c),
+            None => out.remove(to),
+        };
+    }
+    out
+}
+
+/// Build the button and axis maps for each gadget device that the controller named
+/// `name` should drive.
+fn slot_maps(
+    name: &str,
+    config: &Config,
+    profile: Option<&Profile>,
+) -> Vec<(HashMap<Button, Button>, HashMap<Axis, Axis>)> {
+    let button_map = BUTTONS.iter().map(|b| (*b, *b)).collect::<HashMap<_, _>>();
+    let axis_map = AXES.iter().map(|a| (*a, *a)).collect::<HashMap<_, _>>();
+    // If this controller is configured to be split then each slot gets the subset of
+    // the physical controller's inputs that it was configured with.
+    let slot_maps = match config.split_for(name) {
+        Some(split) => split
+            .slots
+            .iter()
+            .map(|slot| {
+                (
+                    select(&button_map, &slot.buttons),
+                    select(&axis_map, &slot.axes),
+                )
+            })
+            .collect(),
+        None => vec![(button_map, axis_map)],
+    };
+    match profile {
+        Some(profile) => slot_maps
+            .iter()
+            .map(|(b, a)| (remap(b, &profile.buttons), remap(a, &profile.axes)))
+            .collect(),
+        None => slot_maps,
+    }
+}
+
+/// Take a gadget device for a controller, preferring `preferred` if it's available and
+/// otherwise avoiding devices that are `reserved` for other known controllers.
+fn take_gadget_device<G: HIDGamepad>(
+    gadget: &mut HIDGadget<G>,
+    preferred: Option<usize>,
+    reserved: &[usize],
+) -> Result<HIDGadgetDeviceFile<G>> {
+    if let Some(index) = preferred {
+        match gadget.take_device_at(index) {
+            Ok(gadget_file) => return Ok(gadget_file),
+            Err(e) => info!("Can't use preferred gadget device: {e}"),
+        }
     }
-    mapping.gadget_file.write_report(report)
+    gadget.take_device_avoiding(reserved)
 }
 
-fn create_and_run_gamepad_gadgets<G: HIDGamepad>(term: Arc<AtomicBool>) -> Result<()> {
-    let mut gadget = HIDGadget::<HoriPokkenPad>::create(1)?;
-    info!(
-        "Created gadget '{:?}' with {} gamepads",
-        gadget.path,
-        gadget.device_count()
-    );
+/// How often to try to open configured evdev devices that aren't connected.
+const EVDEV_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// How long the host can take to configure the gadget once it's started to, before
+/// the gadget is re-enumerated.
+const ENUMERATION_TIMEOUT: Duration = Duration::from_secs(5);
+/// The least time between attempts to re-enumerate the gadget. This doubles after
+/// each attempt, up to `MAX_REBIND_INTERVAL`, until the host configures the gadget
+/// or it's disconnected.
+const REBIND_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_REBIND_INTERVAL: Duration = Duration::from_secs(300);
+/// How often to retry reopening gadget devices after the gadget was rebound.
+const REOPEN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
 
-    let mut gilrs = GilrsBuilder::new()
-        .add_mappings(include_str!("../extra-mappings.txt"))
+fn build_gilrs(mappings: &Mappings) -> Result<Gilrs> {
+    // Only use our mappings, so we know which one gilrs picks for each gamepad.
+    GilrsBuilder::new()
+        .add_included_mappings(false)
+        .add_env_mappings(false)
+        .add_mappings(&mappings.gilrs_mappings())
         .build()
-        .map_err(|e| anyhow!("Error initializing gilrs: {e}"))?;
-    let mut gamepad_mappings = HashMap::new();
+        .map_err(|e| anyhow!("Error initializing gilrs: {e}"))
+}
+
+/// Buttons held down on a gadget device via the control socket.
+struct Injection {
+    slot: usize,
+    buttons: Vec<Button>,
+    until: Instant,
+}
+
+struct Daemon<G: HIDGamepad> {
+    config: Config,
+    /// Where the config was loaded from, so it can be reloaded.
+    config_path: Option<PathBuf>,
+    /// The SDL mappings that `gilrs` was built with.
+    mappings: Mappings,
+    /// Set when a controller has been connected that needs a mapping that
+    /// `mappings` doesn't have yet.
+    mappings_stale: bool,
+    gilrs: Gilrs,
+    gadget: HIDGadget<G>,
+    /// Watches the state of the connection to the host, if the gadget is a USB gadget.
+    udc: Option<UdcWatcher>,
+    /// When the host started enumerating the gadget, if it hasn't finished yet.
+    enumeration_started: Option<Instant>,
+    /// Set when writing a report to a gadget device fails.
+    write_failed: bool,
+    /// When the gadget was last rebound to its UDC.
+    last_rebind: Option<Instant>,
+    /// How long to wait after the last rebind before rebinding again.
+    rebind_interval: Duration,
+    /// When to next try to reopen gadget devices that couldn't be reopened after the
+    /// gadget was rebound.
+    reopen_retry: Option<Instant>,
+    affinity: SlotAffinity,
+    /// Every connected controller, from any source.
+    controllers: HashMap<ControllerId, Controller<G>>,
+    next_controller_id: usize,
+    /// When to next try to open configured evdev devices that aren't open.
+    evdev_retry: Instant,
+    /// Configured evdev devices that have failed to open, so the error is only logged once.
+    evdev_failed: HashSet<PathBuf>,
+    /// The name of the mapping profile currently in use, if any.
+    profile: Option<String>,
+    /// Gadget devices that aren't mapped to a controller but are held open, either
+    /// because they were detached or because button presses are being injected.
+    held_devices: HashMap<usize, HIDGadgetDeviceFile<G>>,
+    /// Gadget devices that were detached via the control socket.
+    detached: HashSet<usize>,
+    injections: Vec<Injection>,
+    /// TAS scripts and recordings being played, by gadget device.
+    playbacks: HashMap<usize, Playback>,
+    /// When to next send every gadget device its report, if reports are sent at a
+    /// fixed rate.
+    next_tick: Option<Instant>,
+    /// How many times reports were sent late enough to miss an interval.
+    overruns: u64,
+    /// Where reports are being recorded to, if anywhere.
+    recorder: Option<Recorder>,
+    /// What's causing the reports being written now, for the recording.
+    cause: String,
+    /// Measures input latency, in latency mode.
+    latency: Option<LatencyTracker>,
+    /// When the input being handled now was read, from whichever source it came from.
+    arrival: Option<Instant>,
+}
+
+impl<G: HIDGamepad> Daemon<G> {
+    fn new(config: Config, config_path: Option<PathBuf>) -> Result<Self> {
+        config.check()?;
+        let mut gadget =
+            HIDGadget::<G>::create_for(config.output, config.gadget_count, &config.usb)?;
+        gadget.set_coalesce(config.coalesce_ms.map(Duration::from_millis));
+        info!(
+            "Created gadget '{:?}' with {} gamepads",
+            gadget.path,
+            gadget.device_count()
+        );
+
+        let mappings = Mappings::load(&config)?;
+        let gilrs = build_gilrs(&mappings)?;
+        let affinity = match &config.slot_affinity_file {
+            Some(path) => SlotAffinity::load(path)?,
+            None => SlotAffinity::default(),
+        };
+        let udc = gadget.udc().map(UdcWatcher::new);
+        let recorder = config
+            .record_file
+            .as_deref()
+            .map(Recorder::create)
+            .transpose()?;
+        Ok(Daemon {
+            profile: config.profile.clone(),
+            next_tick: config.report_interval_ms.map(|_| Instant::now()),
+            config,
+            config_path,
+            mappings,
+            mappings_stale: false,
+            gilrs,
+            gadget,
+            udc,
+            enumeration_started: None,
+            write_failed: false,
+            last_rebind: None,
+            rebind_interval: REBIND_INTERVAL,
+            reopen_retry: None,
+            affinity,
+            controllers: HashMap::new(),
+            next_controller_id: 0,
+            evdev_retry: Instant::now() + EVDEV_RETRY_INTERVAL,
+            evdev_failed: HashSet::new(),
+            held_devices: HashMap::new(),
+            detached: HashSet::new(),
+            injections: vec![],
+            playbacks: HashMap::new(),
+            overruns: 0,
+            recorder,
+            cause: String::new(),
+            latency: None,
+            arrival: None,
+        })
+    }
+
+    fn add_controller(&mut self, name: String, key: String, source: Source) -> ControllerId {
+        let id = ControllerId(self.next_controller_id);
+        self.next_controller_id += 1;
+        self.controllers.insert(
+            id,
+            Controller {
+                name,
+                key,
+                source,
+                mappings: vec![],
+            },
+        );
+        id
+    }
+
+    fn add_gilrs_gamepad(&mut self, gid: GamepadId) -> ControllerId {
+        if let Some(id) = self.gilrs_controller(gid) {
+            return id;
+        }
+        let gamepad = self.gilrs.gamepad(gid);
+        let name = gamepad.name().to_owned();
+        let key = controller_key(gamepad.uuid());
+        self.add_controller(name, key, Source::Gilrs(gid))
+    }
+
+    /// Find the controller for a gilrs gamepad.
+    fn gilrs_controller(&self, gid: GamepadId) -> Option<ControllerId> {
+        self.controllers
+            .iter()
+            .find(|(_, c)| matches!(c.source, Source::Gilrs(g) if g == gid))
+            .map(|(id, _)| *id)
+    }
+
+    /// Whether a gilrs gamepad is also open as an evdev device, in which case
+    /// it's only used through evdev.
+    fn is_evdev_device(&self, gid: GamepadId) -> bool {
+        let gamepad = self.gilrs.gamepad(gid);
+        let key = controller_key(gamepad.uuid());
+        let open_nodes = self
+            .controllers
+            .values()
+            .filter_map(|c| match &c.source {
+                Source::Evdev(device)
+                    if device.info.guid == key && device.info.name == gamepad.os_name() =>
+                {
+                    Some(device.node.as_path())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if open_nodes.is_empty() {
+            return false;
+        }
+        // gilrs doesn't say which device node a gamepad is, so look at all of the
+        // nodes it could be. If they're all open through evdev then this is one of them.
+        let nodes = InputDevice::enumerate()
+            .filter(|d| d.guid == key && d.name == gamepad.os_name())
+            .map(|d| d.path)
+            .collect::<Vec<_>>();
+        if nodes
+            .iter()
+            .all(|node| open_nodes.contains(&node.as_path()))
+        {
+            return true;
+        }
+        warn!(
+            "Can't tell which of {} identical devices ({}) gamepad {} is, so it's assumed to \
+             be one that's open through evdev. Add [[evdev]] entries for all of them to use them all.",
+            nodes.len(),
+            nodes.iter().map(|n| n.display().to_string()).collect::<Vec<_>>().join(", "),
+            gamepad.name(),
+        );
+        true
+    }
 
-    // Iterate over all connected gamepads
-    for (_id, gamepad) in gilrs.gamepads() {
-        if let Err(e) = try_map_gamepad(&gamepad, &mut gadget, &mut gamepad_mappings) {
-            error!("{e}");
+    /// Open any configured evdev devices that aren't open, e.g. because they've
+    /// only just been plugged in.
+    fn open_evdev_devices(&mut self) {
+        for i in 0..self.config.evdev.len() {
+            let path = &self.config.evdev[i].path;
+            let open = self.controllers.values().any(|c| match &c.source {
+                Source::Evdev(device) => device.info.path == *path,
+                _ => false,
+            });
+            if open {
+                continue;
+            }
+            let device = match EvdevController::open(&self.config.evdev[i]) {
+                Ok(device) => device,
+                Err(e) => {
+                    if self.evdev_failed.insert(path.clone()) {
+                        warn!("{e:#}");
+                    }
+                    continue;
+                }
+            };
+            self.evdev_failed.remove(&device.info.path);
+            info!(
+                "Evdev device connected: {} ({})",
+                device.info.name,
+                device.info.path.display()
+            );
+            let name = device.info.name.clone();
+            let key = device.info.guid.clone();
+            let id = self.add_controller(name, key, Source::Evdev(Box::new(device)));
+            // Stop using the same device through gilrs.
+            let duplicates = self
+                .controllers
+                .iter()
+                .filter(
+                    |(_, c)| matches!(c.source, Source::Gilrs(gid) if self.is_evdev_device(gid)),
+                )
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            for duplicate in duplicates {
+                self.unmap_controller(duplicate);
+            }
+            if let Err(e) = self.map_controller(id) {
+                error!("{e}");
+            }
+        }
+        self.open_keyboard_mice();
+    }
+
+    /// Open any configured keyboard and mouse pairs that aren't open.
+    fn open_keyboard_mice(&mut self) {
+        for i in 0..self.config.keyboard_mouse.len() {
+            let path = &self.config.keyboard_mouse[i].keyboard;
+            let open = self.controllers.values().any(|c| match &c.source {
+                Source::KeyboardMouse(kbm) => kbm.path == *path,
+                _ => false,
+            });
+            if open {
+                continue;
+            }
+            let kbm = match KeyboardMouse::open(&self.config.keyboard_mouse[i]) {
+                Ok(kbm) => kbm,
+                Err(e) => {
+                    if self.evdev_failed.insert(path.clone()) {
+                        warn!("{e:#}");
+                    }
+                    continue;
+                }
+            };
+            self.evdev_failed.remove(&kbm.path);
+            info!(
+                "Keyboard and mouse connected: {} ({})",
+                kbm.name,
+                kbm.path.display()
+            );
+            let name = kbm.name.clone();
+            let key = kbm.guid.clone();
+            let id = self.add_controller(name, key, Source::KeyboardMouse(Box::new(kbm)));
+            if let Err(e) = self.map_controller(id) {
+                error!("{e}");
+            }
+        }
+    }
+
+    /// Start listening on the configured network ports.
+    fn open_network_inputs(&mut self) {
+        for i in 0..self.config.network.len() {
+            let input = match NetworkInput::open(&self.config.network[i]) {
+                Ok(input) => input,
+                Err(e) => {
+                    error!("{e:#}");
+                    continue;
+                }
+            };
+            let name = input.name.clone();
+            let key = format!("network:{}", input.listen);
+            // It's mapped once packets start arriving.
+            self.add_controller(name, key, Source::Network(Box::new(input)));
+        }
+        for i in 0..self.config.dsu.len() {
+            let input = match DsuInput::open(&self.config.dsu[i]) {
+                Ok(input) => input,
+                Err(e) => {
+                    error!("{e:#}");
+                    continue;
+                }
+            };
+            let name = input.name.clone();
+            let key = format!("dsu:{}:{}", input.server, input.slot);
+            self.add_controller(name, key, Source::Dsu(Box::new(input)));
         }
     }
 
-    while !term.load(Ordering::Relaxed) {
-        while let Some(Event { id, event, .. }) = gilrs.next_event() {
+    /// Stop listening on the network ports and start again with the current config.
+    fn reopen_network_inputs(&mut self) {
+        let ids = self
+            .controllers
+            .iter()
+            .filter(|(_, c)| matches!(c.source, Source::Network(_) | Source::Dsu(_)))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.remove_controller(id);
+        }
+        self.open_network_inputs();
+    }
+
+    /// Read packets from the network inputs, mapping them when they start
+    /// arriving and unmapping them when they stop.
+    fn poll_network_inputs(&mut self) {
+        let mut events = vec![];
+        for (id, controller) in self.controllers.iter_mut() {
+            match &mut controller.source {
+                Source::Network(input) => events.push((*id, input.poll())),
+                Source::Dsu(input) => events.push((*id, input.poll())),
+                _ => {}
+            }
+        }
+        self.arrival = Some(Instant::now());
+        for (id, event) in events {
+            if event == NetworkEvent::Nothing {
+                continue;
+            }
+            self.cause = format!("network {} {event:?}", self.controllers[&id].name);
             match event {
-                EventType::Connected => {
-                    let gamepad = gilrs.gamepad(id);
-                    if let Err(e) = try_map_gamepad(&gamepad, &mut gadget, &mut gamepad_mappings) {
+                NetworkEvent::Nothing => {}
+                NetworkEvent::Changed => self.update_controller(id),
+                NetworkEvent::Connected => {
+                    info!(
+                        "Network controller connected: {}",
+                        self.controllers[&id].name
+                    );
+                    if let Err(e) = self.map_controller(id) {
                         error!("{e}");
                     }
+                    self.update_controller(id);
+                }
+                NetworkEvent::Disconnected => {
+                    info!(
+                        "Network controller disconnected: {}",
+                        self.controllers[&id].name
+                    );
+                    self.unmap_controller(id);
+                }
+            }
+        }
+        self.arrival = None;
+    }
+
+    /// Every open gadget device.
+    fn gadget_files_mut(&mut self) -> impl Iterator<Item = &mut HIDGadgetDeviceFile<G>> {
+        self.controllers
+            .values_mut()
+            .flat_map(|c| c.mappings.iter_mut().map(|m| &mut m.gadget_file))
+            .chain(self.held_devices.values_mut())
+    }
+
+    /// Whether gadget device `slot` has a report waiting for the host to read it.
+    fn slot_has_pending(&mut self, slot: usize) -> bool {
+        self.gadget_files_mut()
+            .any(|f| f.index() == slot && f.has_pending())
+    }
+
+    /// Send any reports that were held back for coalescing once their time is up.
+    fn flush_reports(&mut self) {
+        let mut sent = vec![];
+        let mut failed = false;
+        for gadget_file in self.gadget_files_mut() {
+            match gadget_file.flush_pending() {
+                Ok(true) => sent.push((
+                    gadget_file.index(),
+                    gadget_file.last_report().to_vec(),
+                    gadget_file.pending_cause().to_owned(),
+                )),
+                Ok(false) => {}
+                Err(e) => {
+                    debug!("Failed to write to {}: {e}", gadget_file.path());
+                    failed = true;
+                }
+            }
+        }
+        self.write_failed |= failed;
+        if let Some(latency) = &mut self.latency {
+            for (slot, report, _) in &sent {
+                latency.written(*slot, report);
+            }
+        }
+        if let Some(recorder) = &mut self.recorder {
+            for (slot, report, cause) in sent {
+                if let Err(e) = recorder.record(slot, &report, &cause) {
+                    error!("Failed to record report, stopping recording: {e}");
+                    self.recorder = None;
+                    break;
                 }
-                EventType::Disconnected => {
-                    info!("Gamepad disconnected: {id}");
-                    if let Some(RealGamepadToGadgetMapping { gadget_file, .. }) =
-                        gamepad_mappings.remove(&id)
-                    {
-                        let _ = gadget.release_device(gadget_file);
+            }
+        }
+    }
+
+    /// Read any output reports the host has sent to the open gadget devices, so that
+    /// the gamepad can reply to them.
+    fn poll_output_reports(&mut self) {
+        for gadget_file in self.gadget_files_mut() {
+            if let Err(e) = gadget_file.check_read_report() {
+                debug!("Failed to read from {}: {e}", gadget_file.path());
+            }
+        }
+    }
+
+    /// Read input from the evdev devices, and drop any that have been disconnected.
+    fn poll_evdev_devices(&mut self) {
+        let mut changed = vec![];
+        let mut gone = vec![];
+        for (id, controller) in self.controllers.iter_mut() {
+            let res = match &mut controller.source {
+                Source::Evdev(device) => device.poll(),
+                Source::KeyboardMouse(kbm) => kbm.poll(),
+                Source::Gilrs(_) | Source::Network(_) | Source::Dsu(_) => continue,
+            };
+            match res {
+                Ok(true) => changed.push(*id),
+                Ok(false) => {}
+                Err(e) => {
+                    info!("Evdev device disconnected: {} ({e})", controller.name);
+                    gone.push(*id);
+                }
+            }
+        }
+        self.arrival = Some(Instant::now());
+        for id in changed {
+            self.cause = format!("evdev {}", self.controllers[&id].name);
+            self.update_controller(id);
+        }
+        self.arrival = None;
+        for id in gone {
+            self.remove_controller(id);
+        }
+    }
+
+    /// Map any connected controllers that aren't currently mapped.
+    fn map_unmapped_controllers(&mut self) {
+        let ids = self
+            .controllers
+            .iter()
+            .filter(|(_, c)| c.mappings.is_empty())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            if let Err(e) = self.map_controller(id) {
+                error!("{e}");
+            }
+        }
+    }
+
+    /// Check whether a gilrs gamepad can be mapped.
+    ///
+    /// Returns `false` if it shouldn't be mapped yet, but isn't in error.
+    fn check_gilrs_gamepad(&mut self, gid: GamepadId, key: &str) -> Result<bool> {
+        if self.is_evdev_device(gid) {
+            return Ok(false);
+        }
+        let gamepad = self.gilrs.gamepad(gid);
+        if gamepad.mapping_source() != MappingSource::SdlMappings {
+            // If it's configured to use a raw mapping, but we haven't built one
+            // for it yet, build one and try again.
+            if self.config.raw_mapping_for(gamepad.name()).is_some() && !self.mappings.contains(key)
+            {
+                info!("Building a raw mapping for {}", gamepad.name());
+                self.mappings_stale = true;
+                return Ok(false);
+            }
+            bail!("Not using gamepad {}, no mapping data", gamepad.name());
+        }
+        if let Some(origin) = self.mappings.origin(key, gamepad.map_name()) {
+            info!("Using mapping for {} from {origin}", gamepad.name());
+        }
+        Ok(true)
+    }
+
+    fn map_controller(&mut self, id: ControllerId) -> Result<()> {
+        let Some(controller) = self.controllers.get(&id) else {
+            return Ok(());
+        };
+        if !controller.mappings.is_empty() {
+            return Ok(());
+        }
+        let name = controller.name.clone();
+        let key = controller.key.clone();
+        // Network inputs are only mapped while packets are arriving.
+        let inactive = match &controller.source {
+            Source::Network(input) => !input.is_active(),
+            Source::Dsu(input) => !input.is_active(),
+            _ => false,
+        };
+        if inactive {
+            return Ok(());
+        }
+        if let Source::Gilrs(gid) = controller.source {
+            if !self.check_gilrs_gamepad(gid, &key)? {
+                return Ok(());
+            }
+        }
+        // Let go of any devices we're only holding open for injected presses,
+        // this controller might want one of them.
+        self.release_idle_devices();
+        let profile = self
+            .profile
+            .as_ref()
+            .and_then(|n| self.config.profiles.get(n));
+        let slot_maps = slot_maps(&name, &self.config, profile);
+        let preferred = self.affinity.slots_for(&key).to_vec();
+        let reserved = self.affinity.reserved_except(&key);
+        let mut gadget_mappings = vec![];
+        for (i, (button_map, axis_map)) in slot_maps.into_iter().enumerate() {
+            let gadget_file =
+                match take_gadget_device(&mut self.gadget, preferred.get(i).copied(), &reserved) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        // Don't hold on to a partial set of gadget devices.
+                        for RealGamepadToGadgetMapping { gadget_file, .. } in gadget_mappings {
+                            let _ = self.gadget.release_device(gadget_file);
+                        }
+                        return Err(e);
                     }
+                };
+            info!("Mapping {name} to {}", gadget_file.path());
+            log_mapping(&button_map, &axis_map);
+            gadget_mappings.push(RealGamepadToGadgetMapping {
+                button_map,
+                axis_map,
+                gadget_file,
+            });
+        }
+        if let Some(controller) = self.controllers.get_mut(&id) {
+            controller.mappings = gadget_mappings;
+        }
+        self.record_affinity(id);
+        Ok(())
+    }
+
+    fn unmap_controller(&mut self, id: ControllerId) {
+        let mappings = self
+            .controllers
+            .get_mut(&id)
+            .map(|c| mem::take(&mut c.mappings))
+            .unwrap_or_default();
+        for RealGamepadToGadgetMapping { gadget_file, .. } in mappings {
+            let _ = self.gadget.release_device(gadget_file);
+        }
+    }
+
+    /// Unmap a controller that has been disconnected and forget about it.
+    fn remove_controller(&mut self, id: ControllerId) {
+        self.unmap_controller(id);
+        self.controllers.remove(&id);
+    }
+
+    /// Rebuild the button and axis maps of every mapped controller, keeping their gadget devices.
+    ///
+    /// Controllers whose number of gadget devices has changed are unmapped and mapped again.
+    fn remap_controllers(&mut self) {
+        let profile = self
+            .profile
+            .as_ref()
+            .and_then(|n| self.config.profiles.get(n));
+        let mut changed = vec![];
+        for (id, controller) in self.controllers.iter_mut() {
+            if controller.mappings.is_empty() {
+                continue;
+            }
+            let slot_maps = slot_maps(&controller.name, &self.config, profile);
+            if slot_maps.len() != controller.mappings.len() {
+                changed.push(*id);
+                continue;
+            }
+            for (mapping, (button_map, axis_map)) in controller.mappings.iter_mut().zip(slot_maps) {
+                info!(
+                    "Remapping {} on {}",
+                    controller.name,
+                    mapping.gadget_file.path()
+                );
+                log_mapping(&button_map, &axis_map);
+                mapping.button_map = button_map;
+                mapping.axis_map = axis_map;
+            }
+        }
+        for id in changed {
+            self.unmap_controller(id);
+            if let Err(e) = self.map_controller(id) {
+                error!("{e}");
+            }
+        }
+        let ids = self.controllers.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            self.update_controller(id);
+        }
+    }
+
+    fn record_affinity(&mut self, id: ControllerId) {
+        let Some(controller) = self.controllers.get(&id) else {
+            return;
+        };
+        let slots = controller
+            .mappings
+            .iter()
+            .map(|m| m.gadget_file.index())
+            .collect();
+        self.affinity.assign(&controller.key, slots);
+    }
+
+    /// Find the controller mapped to gadget device `slot`, and the index of that mapping.
+    fn slot_owner(&self, slot: usize) -> Option<(ControllerId, usize)> {
+        self.controllers.iter().find_map(|(id, controller)| {
+            controller
+                .mappings
+                .iter()
+                .position(|m| m.gadget_file.index() == slot)
+                .map(|i| (*id, i))
+        })
+    }
+
+    fn slot_report(&self, slot: usize) -> G::Report {
+        G::fill_report(&self.slot_state(slot))
+    }
+
+    /// The state of the controller driving gadget device `slot`, with any injected
+    /// button presses.
+    fn slot_state(&self, slot: usize) -> ControllerState {
+        let mut state = match self.slot_owner(slot) {
+            Some((id, i)) => {
+                let controller = &self.controllers[&id];
+                let mapping = &controller.mappings[i];
+                let state = match &controller.source {
+                    Source::Gilrs(gid) => ControllerState::from(&self.gilrs.gamepad(*gid)),
+                    Source::Evdev(device) => device.state(),
+                    Source::KeyboardMouse(kbm) => kbm.state(),
+                    Source::Network(input) => input.state(),
+                    Source::Dsu(input) => input.state(),
+                };
+                state.remapped(&mapping.button_map, &mapping.axis_map)
+            }
+            None => ControllerState::default(),
+        };
+        for injection in self.injections.iter().filter(|i| i.slot == slot) {
+            state.press(&injection.buttons);
+        }
+        state
+    }
+
+    /// Write the current report for gadget device `slot`.
+    ///
+    /// When reports are sent at a fixed rate this does nothing, since the report will
+    /// be sent at the next tick along with anything else that changes before then.
+    fn write_slot(&mut self, slot: usize) -> Result<()> {
+        if let (Some(latency), Some(arrival)) = (&mut self.latency, self.arrival) {
+            latency.changed(slot, arrival);
+        }
+        if self.next_tick.is_some() {
+            return Ok(());
+        }
+        self.send_report(slot, false)
+    }
+
+    /// Write the current report for gadget device `slot` now. Unless `force` is set
+    /// the report is skipped if it hasn't changed, or held if it's too soon after
+    /// the last one.
+    fn send_report(&mut self, slot: usize, force: bool) -> Result<()> {
+        // The current state is sent when the host is ready again.
+        if !self.host_ready() {
+            if let Some(latency) = &mut self.latency {
+                latency.discard(slot);
+            }
+            if self.udc.as_ref().and_then(UdcWatcher::state) == Some(UdcState::Suspended) {
+                self.check_wake(slot);
+            }
+            return Ok(());
+        }
+        // A playback takes the place of the controller's input.
+        let (report, bytes) = match self.playbacks.get(&slot).map(Playback::frame) {
+            Some(Frame::State(state)) => (G::fill_report(state), None),
+            Some(Frame::Report(bytes)) => (G::Report::default(), Some(bytes.clone())),
+            None => (self.slot_report(slot), None),
+        };
+        let gadget_file = self
+            .controllers
+            .values_mut()
+            .flat_map(|c| c.mappings.iter_mut())
+            .map(|m| &mut m.gadget_file)
+            .chain(self.held_devices.values_mut())
+            .find(|f| f.index() == slot);
+        match gadget_file {
+            Some(gadget_file) => {
+                if bytes.is_none() && log_enabled!(Level::Debug) {
+                    debug!("{}: {report}", gadget_file.path());
+                }
+                let sent = match (bytes, force) {
+                    (Some(bytes), true) => gadget_file.force_write_report_bytes(&bytes),
+                    (Some(bytes), false) => gadget_file.write_report_bytes(&bytes),
+                    (None, true) => gadget_file.force_write_report(report),
+                    (None, false) => gadget_file.write_report(report),
+                };
+                let sent = match sent {
+                    Ok(sent) => sent,
+                    Err(e) => {
+                        self.write_failed = true;
+                        return Err(e);
+                    }
+                };
+                if !sent {
+                    if gadget_file.has_pending() {
+                        gadget_file.set_pending_cause(&self.cause);
+                    } else if let Some(latency) = &mut self.latency {
+                        latency.discard(slot);
+                    }
+                    return Ok(());
+                }
+                if let Some(latency) = &mut self.latency {
+                    latency.written(slot, gadget_file.last_report());
                 }
-                EventType::Dropped => {}
-                // If the gamepad we're providing doesn't have any analog buttons then we don't need
-                // to handle ButtonChanged events, and since gilrs will generate one per press/release
-                // that would cause us to write an extra report every time.
-                EventType::ButtonChanged(_, _, _) if !G::ANALOG_BUTTONS => {}
-                _ => {
-                    let gamepad = gilrs.gamepad(id);
-                    let mapping = gamepad_mappings.get_mut(&id);
-                    if let Some(mapping) = mapping {
-                        let _ = update_gamepad(&gamepad, mapping);
+                if let Some(recorder) = &mut self.recorder {
+                    if let Err(e) = recorder.record(slot, gadget_file.last_report(), &self.cause) {
+                        error!("Failed to record report, stopping recording: {e}");
+                        self.recorder = None;
                     }
                 }
+                Ok(())
             }
+            None => bail!("Gadget device {slot} is not open"),
         }
     }
-    // Release any in-use gadgets before cleaning up for real.
-    for mapping in gamepad_mappings.into_values() {
-        let RealGamepadToGadgetMapping { gadget_file, .. } = mapping;
-        let _ = gadget.release_device(gadget_file);
+
+    fn update_controller(&mut self, id: ControllerId) {
+        let slots = self
+            .controllers
+            .get(&id)
+            .into_iter()
+            .flat_map(|c| c.mappings.iter())
+            .map(|m| m.gadget_file.index())
+            .collect::<Vec<_>>();
+        // A split controller will update multiple gadget devices. Gadget devices that
+        // are playing something keep sending that until it's done.
+        for slot in slots {
+            if self.playbacks.contains_key(&slot) {
+                continue;
+            }
+            let _ = self.write_slot(slot);
+        }
     }
+
+    /// Make sure gadget device `slot` is open, holding it if no gamepad is using it.
+    fn ensure_slot_open(&mut self, slot: usize) -> Result<()> {
+        if self.slot_owner(slot).is_none() && !self.held_devices.contains_key(&slot) {
+            let gadget_file = self.gadget.take_device_at(slot)?;
+            self.held_devices.insert(slot, gadget_file);
+        }
+        Ok(())
+    }
+
+    /// Release any held devices that aren't detached and don't have injected presses.
+    fn release_idle_devices(&mut self) {
+        let idle = self
+            .held_devices
+            .keys()
+            .filter(|i| {
+                !self.detached.contains(i)
+                    && !self.injections.iter().any(|j| j.slot == **i)
+                    && !self.playbacks.contains_key(i)
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        for i in idle {
+            if let Some(gadget_file) = self.held_devices.remove(&i) {
+                let _ = self.gadget.release_device(gadget_file);
+            }
+        }
+    }
+
+    fn expire_injections(&mut self) {
+        let now = Instant::now();
+        let mut expired = self
+            .injections
+            .iter()
+            .filter(|i| i.until <= now)
+            .map(|i| i.slot)
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return;
+        }
+        expired.dedup();
+        self.injections.retain(|i| i.until > now);
+        self.cause = "injection expired".to_owned();
+        for slot in expired {
+            // Send this now rather than at the next tick, since the gadget device may
+            // be closed before then.
+            if let Err(e) = self.send_report(slot, false) {
+                error!("Failed to release injected buttons: {e}");
+            }
+        }
+        self.release_idle_devices();
+    }
+
+    fn check_slot(&self, slot: usize) -> Result<()> {
+        if slot >= self.gadget.device_count() {
+            bail!("No such gadget device: {slot}");
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, request: Request) -> Response {
+        let res = match request {
+            Request::Status => {
+                return Response::Status {
+                    daemon: self.status(),
+                }
+            }
+            Request::ListControllers => {
+                return Response::Controllers {
+                    controllers: self.list_controllers(),
+                }
+            }
+            Request::ListSlots => {
+                return Response::Slots {
+                    slots: self.list_slots(),
+                }
+            }
+            Request::Assign { controller, slot } => self.assign(controller, slot),
+            Request::Detach { slot } => self.detach(slot),
+            Request::Attach { slot } => self.attach(slot),
+            Request::Profile { name } => self.set_profile(name),
+            Request::Press {
+                slot,
+                buttons,
+                hold_ms,
+            } => self.press(slot, buttons, Duration::from_millis(hold_ms)),
+            Request::Reload => self.reload(),
+            Request::Play { slot, file } => self.play(slot, &file),
+            Request::Stop { slot } => self.stop(slot),
+        };
+        match res {
+            Ok(()) => Response::Ok,
+            Err(e) => e.into(),
+        }
+    }
+
+    fn status(&self) -> DaemonStatus {
+        let mut profiles = self.config.profiles.keys().cloned().collect::<Vec<_>>();
+        profiles.sort();
+        DaemonStatus {
+            gadget_path: self.gadget.path.display().to_string(),
+            slot_count: self.gadget.device_count(),
+            profile: self.profile.clone(),
+            profiles,
+            report_interval_ms: self.config.report_interval_ms,
+            overruns: self.overruns,
+            host_state: self
+                .udc
+                .as_ref()
+                .and_then(UdcWatcher::state)
+                .map(|state| state.to_string()),
+            controllers: self.list_controllers(),
+        }
+    }
+
+    fn list_slots(&self) -> Vec<SlotStatus> {
+        (0..self.gadget.device_count())
+            .map(|index| {
+                let owner = self.slot_owner(index);
+                let path = match owner {
+                    Some((id, i)) => Some(self.controllers[&id].mappings[i].gadget_file.path()),
+                    None => self
+                        .held_devices
+                        .get(&index)
+                        .map(|f| f.path())
+                        .or_else(|| self.gadget.device_path(index)),
+                };
+                SlotStatus {
+                    index,
+                    path: path.unwrap_or_default().to_owned(),
+                    controller: owner.map(|(id, _)| id),
+                    detached: self.detached.contains(&index),
+                    playing: self.playbacks.get(&index).map(|p| p.path.clone()),
+                }
+            })
+            .collect()
+    }
+
+    fn list_controllers(&self) -> Vec<ControllerInfo> {
+        let mut ids = self.controllers.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        ids.into_iter()
+            .map(|id| {
+                let controller = &self.controllers[&id];
+                let mapping = match &controller.source {
+                    Source::Gilrs(gid) => self
+                        .mappings
+                        .origin(&controller.key, self.gilrs.gamepad(*gid).map_name())
+                        .map(|o| o.to_string()),
+                    Source::Evdev(_) => Some("evdev config".to_owned()),
+                    Source::KeyboardMouse(_) => Some("keyboard_mouse config".to_owned()),
+                    Source::Network(_) => Some("network config".to_owned()),
+                    Source::Dsu(_) => Some("dsu config".to_owned()),
+                };
+                ControllerInfo {
+                    id,
+                    name: controller.name.clone(),
+                    key: controller.key.clone(),
+                    mapping,
+                    slots: controller
+                        .mappings
+                        .iter()
+                        .map(|m| SlotInfo {
+                            index: m.gadget_file.index(),
+                            path: m.gadget_file.path().to_owned(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    fn assign(&mut self, controller: ControllerId, slot: usize) -> Result<()> {
+        self.check_slot(slot)?;
+        if self.detached.contains(&slot) {
+            bail!("Gadget device {slot} is detached");
+        }
+        let current = match self
+            .controllers
+            .get(&controller)
+            .and_then(|c| c.mappings.first())
+        {
+            Some(mapping) => mapping.gadget_file.index(),
+            None => bail!("Controller {controller} is not mapped"),
+        };
+        if current == slot {
+            return Ok(());
+        }
+        self.release_idle_devices();
+        let owner = self.slot_owner(slot);
+        match owner {
+            // The slot is another of this controller's split slots, so swap which of
+            // its mappings drives each of the two gadget devices.
+            Some((other, i)) if other == controller => {
+                let mappings = &mut self.controllers.get_mut(&controller).unwrap().mappings;
+                let (first, rest) = mappings.split_first_mut().unwrap();
+                mem::swap(&mut first.gadget_file, &mut rest[i - 1].gadget_file);
+            }
+            // Another controller is using the slot, so swap their gadget devices.
+            Some((other, i)) => {
+                let mut other_mappings =
+                    mem::take(&mut self.controllers.get_mut(&other).unwrap().mappings);
+                let mappings = &mut self.controllers.get_mut(&controller).unwrap().mappings;
+                mem::swap(
+                    &mut mappings[0].gadget_file,
+                    &mut other_mappings[i].gadget_file,
+                );
+                self.controllers.get_mut(&other).unwrap().mappings = other_mappings;
+            }
+            None => {
+                let gadget_file = self.gadget.take_device_at(slot)?;
+                let mappings = &mut self.controllers.get_mut(&controller).unwrap().mappings;
+                let mut old = mem::replace(&mut mappings[0].gadget_file, gadget_file);
+                // Nothing drives the old gadget device any more, so release everything
+                // on it before letting it go.
+                if let Err(e) = old.force_write_report(G::Report::default()) {
+                    warn!("Failed to release the inputs on gadget device {current}: {e:#}");
+                }
+                self.gadget.release_device(old)?;
+            }
+        }
+        info!("Assigned controller {controller} to gadget device {slot}");
+        self.record_affinity(controller);
+        self.update_controller(controller);
+        if let Some((other, _)) = owner.filter(|(other, _)| *other != controller) {
+            self.record_affinity(other);
+            self.update_controller(other);
+        }
+        Ok(())
+    }
+
+    fn detach(&mut self, slot: usize) -> Result<()> {
+        self.check_slot(slot)?;
+        if self.detached.contains(&slot) {
+            bail!("Gadget device {slot} is already detached");
+        }
+        // The controller using this slot is unmapped entirely, and will be mapped again
+        // when the slot is attached.
+        if let Some((id, _)) = self.slot_owner(slot) {
+            info!("Unmapping controller {id} from detached gadget device {slot}");
+            self.unmap_controller(id);
+        }
+        self.ensure_slot_open(slot)?;
+        self.detached.insert(slot);
+        info!("Detached gadget device {slot}");
+        self.write_slot(slot)
+    }
+
+    fn attach(&mut self, slot: usize) -> Result<()> {
+        self.check_slot(slot)?;
+        if !self.detached.remove(&slot) {
+            bail!("Gadget device {slot} is not detached");
+        }
+        info!("Attached gadget device {slot}");
+        self.release_idle_devices();
+        self.map_unmapped_controllers();
+        Ok(())
+    }
+
+    fn set_profile(&mut self, name: Option<String>) -> Result<()> {
+        if let Some(name) = &name {
+            if !self.config.profiles.contains_key(name) {
+                bail!("No such profile: {name}");
+            }
+        }
+        info!("Switching to profile {name:?}");
+        self.profile = name;
+        self.remap_controllers();
+        Ok(())
+    }
+
+    /// Reload the config file and mapping file, remapping all controllers with the new settings.
+    ///
+    /// Gadget devices are kept open throughout so the host doesn't see a disconnect.
+    fn reload(&mut self) -> Result<()> {
+        let config = match &self.config_path {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        config.check()?;
+        let mappings = Mappings::load(&config)?;
+        // gilrs can't change its mappings after it's built, so if they've changed
+        // build a new instance and map all the gamepads from scratch.
+        let gilrs = if mappings.gilrs_mappings() != self.mappings.gilrs_mappings() {
+            Some(build_gilrs(&mappings)?)
+        } else {
+            None
+        };
+        if config.gadget_count != self.config.gadget_count
+            || config.output != self.config.output
+            || config.usb != self.config.usb
+            || config.control_socket != self.config.control_socket
+            || config.slot_affinity_file != self.config.slot_affinity_file
+        {
+            warn!("Some config changes will only take effect after a restart");
+        }
+        // Keep using the current profile if it still exists.
+        if !self
+            .profile
+            .as_ref()
+            .is_some_and(|name| config.profiles.contains_key(name))
+        {
+            self.profile = config.profile.clone();
+        }
+        let evdev_changed = config.evdev != self.config.evdev
+            || config.keyboard_mouse != self.config.keyboard_mouse;
+        let network_changed =
+            config.network != self.config.network || config.dsu != self.config.dsu;
+        if config.coalesce_ms != self.config.coalesce_ms {
+            let window = config.coalesce_ms.map(Duration::from_millis);
+            self.gadget.set_coalesce(window);
+            for gadget_file in self.gadget_files_mut() {
+                gadget_file.set_coalesce(window);
+            }
+        }
+        if config.report_interval_ms != self.config.report_interval_ms {
+            self.next_tick = config.report_interval_ms.map(|_| Instant::now());
+        }
+        // Start a new recording if the file has changed, but keep going with the
+        // current one if it hasn't.
+        if config.record_file != self.config.record_file {
+            self.recorder = config
+                .record_file
+                .as_deref()
+                .map(Recorder::create)
+                .transpose()?;
+        }
+        info!("Reloaded config");
+        self.config = config;
+        self.mappings = mappings;
+        if evdev_changed {
+            self.reopen_evdev_devices();
+        }
+        if network_changed {
+            self.reopen_network_inputs();
+        }
+        match gilrs {
+            Some(gilrs) => self.replace_gilrs(gilrs),
+            None => self.remap_controllers(),
+        }
+        Ok(())
+    }
+
+    /// Close all evdev devices and open the configured ones again.
+    fn reopen_evdev_devices(&mut self) {
+        let ids = self
+            .controllers
+            .iter()
+            .filter(|(_, c)| matches!(c.source, Source::Evdev(_) | Source::KeyboardMouse(_)))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.remove_controller(id);
+        }
+        self.evdev_failed.clear();
+        self.open_evdev_devices();
+        // Gamepads that were only being read through evdev can use gilrs again.
+        self.map_unmapped_controllers();
+    }
+
+    /// Load the mappings again to pick up raw mappings for newly connected controllers.
+    fn refresh_mappings(&mut self) -> Result<()> {
+        let mappings = Mappings::load(&self.config)?;
+        if mappings.gilrs_mappings() == self.mappings.gilrs_mappings() {
+            return Ok(());
+        }
+        let gilrs = build_gilrs(&mappings)?;
+        self.mappings = mappings;
+        self.replace_gilrs(gilrs);
+        Ok(())
+    }
+
+    /// Switch to a new gilrs instance, which has different mappings, and map all
+    /// of its gamepads from scratch.
+    fn replace_gilrs(&mut self, gilrs: Gilrs) {
+        info!("SDL mappings changed, reinitializing gilrs");
+        let ids = self
+            .controllers
+            .iter()
+            .filter(|(_, c)| matches!(c.source, Source::Gilrs(_)))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.remove_controller(id);
+        }
+        self.gilrs = gilrs;
+        let gids = self
+            .gilrs
+            .gamepads()
+            .map(|(gid, _)| gid)
+            .collect::<Vec<_>>();
+        for gid in gids {
+            self.add_gilrs_gamepad(gid);
+        }
+        self.map_unmapped_controllers();
+        let ids = self.controllers.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            self.update_controller(id);
+        }
+    }
+
+    fn press(&mut self, slot: usize, buttons: Vec<Button>, hold: Duration) -> Result<()> {
+        self.check_slot(slot)?;
+        self.ensure_slot_open(slot)?;
+        self.injections.push(Injection {
+            slot,
+            buttons,
+            until: Instant::now() + hold,
+        });
+        self.write_slot(slot)
+    }
+
+    fn play(&mut self, slot: usize, file: &Path) -> Result<()> {
+        self.check_slot(slot)?;
+        let playback = Playback::load(file, slot, G::report_size())?;
+        self.ensure_slot_open(slot)?;
+        info!("Playing {file:?} to gadget device {slot}");
+        self.playbacks.insert(slot, playback);
+        Ok(())
+    }
+
+    fn stop(&mut self, slot: usize) -> Result<()> {
+        match self.playbacks.remove(&slot) {
+            Some(playback) => info!(
+                "Stopped playing {:?} to gadget device {slot}",
+                playback.path
+            ),
+            None => bail!("Nothing is playing to gadget device {slot}"),
+        }
+        self.cause = "playback stopped".to_owned();
+        let res = self.send_report(slot, false);
+        self.release_idle_devices();
+        res
+    }
+
+    /// Send the next reports of any playbacks that are due, and go back to the
+    /// controllers' input once they're finished.
+    fn poll_playbacks(&mut self) {
+        if self.playbacks.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let slots = self.playbacks.keys().copied().collect::<Vec<_>>();
+        for slot in slots {
+            // Every frame is sent, so don't move on while the host hasn't taken the
+            // last one yet. Frames that are late are sent as fast as the host reads them.
+            while self.host_ready() && !self.slot_has_pending(slot) {
+                let Some(playback) = self.playbacks.get_mut(&slot) else {
+                    break;
+                };
+                if playback.is_finished() || !playback.poll(now) {
+                    break;
+                }
+                self.cause = format!("playback {:?}", playback.path);
+                // The controller that had the gadget device may have gone away, in
+                // which case the playback holds it itself. Playback frames are sent
+                // even if they're the same as the last one, and aren't coalesced.
+                let res = self
+                    .ensure_slot_open(slot)
+                    .and_then(|_| self.send_report(slot, true));
+                if let Err(e) = res {
+                    error!("Failed to play to gadget device {slot}: {e}");
+                    break;
+                }
+            }
+        }
+        let finished = self
+            .playbacks
+            .iter()
+            .filter(|(_, p)| p.is_finished())
+            .map(|(slot, _)| *slot)
+            .collect::<Vec<_>>();
+        for slot in finished {
+            if let Some(playback) = self.playbacks.remove(&slot) {
+                info!(
+                    "Finished playing {:?} to gadget device {slot}",
+                    playback.path
+                );
+            }
+            self.cause = "playback finished".to_owned();
+            let _ = self.send_report(slot, false);
+            self.release_idle_devices();
+        }
+    }
+
+    /// Send every open gadget device its latest report, if reports are sent at a
+    /// fixed rate and it's time to.
+    fn tick(&mut self) {
+        let (Some(next_tick), Some(interval)) = (self.next_tick, self.config.report_interval_ms)
+        else {
+            return;
+        };
+        let now = Instant::now();
+        if now < next_tick {
+            return;
+        }
+        let interval = Duration::from_millis(interval);
+        // Skip any ticks that were missed, rather than sending a burst of reports.
+        let missed = ((now - next_tick).as_nanos() / interval.as_nanos()) as u32;
+        if missed > 0 {
+            debug!("Report tick was {:?} late", now - next_tick);
+            self.overruns += missed as u64;
+        }
+        self.next_tick = Some(next_tick + interval * (missed + 1));
+        self.cause = "tick".to_owned();
+        self.send_all_reports();
+    }
+
+    /// The indexes of every open gadget device.
+    fn open_slots(&self) -> Vec<usize> {
+        self.controllers
+            .values()
+            .flat_map(|c| c.mappings.iter())
+            .map(|m| m.gadget_file.index())
+            .chain(self.held_devices.keys().copied())
+            .collect()
+    }
+
+    /// Send every open gadget device its current report, whether it's changed or not.
+    fn send_all_reports(&mut self) {
+        for slot in self.open_slots() {
+            if let Err(e) = self.send_report(slot, true) {
+                debug!("Failed to send report to gadget device {slot}: {e}");
+            }
+        }
+    }
+
+    /// Whether the host is ready for reports. Reports are only sent once it has
+    /// configured the gadget, and not while it's suspended. Without a UDC (like with
+    /// uhid output), or if its state can't be read, the host is assumed to be ready.
+    fn host_ready(&self) -> bool {
+        self.udc
+            .as_ref()
+            .and_then(UdcWatcher::state)
+            .is_none_or(|state| state == UdcState::Configured)
+    }
+
+    /// Wake the host if a wake button is pressed on gadget device `slot`.
+    fn check_wake(&mut self, slot: usize) {
+        let state = self.slot_state(slot);
+        if !self
+            .config
+            .wake_buttons
+            .iter()
+            .any(|b| state.is_pressed(*b))
+        {
+            return;
+        }
+        let Some(udc) = &mut self.udc else {
+            return;
+        };
+        match udc.wake_host() {
+            Ok(true) => info!("Waking the host for gadget device {slot}"),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to wake the host: {e:#}"),
+        }
+    }
+
+    /// Check whether the state of the connection to the host has changed.
+    fn poll_udc(&mut self) {
+        let Some(udc) = &mut self.udc else {
+            return;
+        };
+        let previous = udc.state();
+        let Some(state) = udc.poll() else {
+            return;
+        };
+        match (previous, state) {
+            (_, UdcState::Suspended) => {
+                info!("The host suspended the gadget (it's probably asleep), holding reports")
+            }
+            (Some(UdcState::Suspended), UdcState::Configured) => {
+                info!("The host resumed the gadget")
+            }
+            (_, UdcState::Configured) => info!("The host configured the gadget"),
+            (_, UdcState::NotAttached) => info!("The gadget was disconnected from the host"),
+            _ => debug!("UDC {} is {state}", udc.name()),
+        }
+        match state {
+            UdcState::Configured | UdcState::Suspended | UdcState::NotAttached => {
+                self.enumeration_started = None;
+                // Re-enumerating worked, or there's no host to enumerate the gadget,
+                // so the next problem can be retried quickly again.
+                if state != UdcState::Suspended {
+                    self.rebind_interval = REBIND_INTERVAL;
+                }
+            }
+            _ => {
+                self.enumeration_started.get_or_insert_with(Instant::now);
+            }
+        }
+        if state == UdcState::Configured {
+            // Nothing was sent while the host wasn't ready, so catch it up.
+            self.cause = format!("host {state}");
+            self.send_all_reports();
+        }
+    }
+
+    /// Re-enumerate the gadget if writing reports has failed, or if the host has been
+    /// stuck partway through enumerating it, which can happen after the host resets
+    /// the bus or the cable is replugged.
+    fn check_enumeration(&mut self) {
+        if self.reopen_retry.is_some_and(|t| Instant::now() >= t) {
+            self.reopen_gadget_files();
+        }
+        if self.udc.is_none() {
+            self.write_failed = false;
+            return;
+        }
+        let stuck = self
+            .enumeration_started
+            .is_some_and(|t| t.elapsed() >= ENUMERATION_TIMEOUT);
+        if !stuck && !self.write_failed {
+            return;
+        }
+        if self
+            .last_rebind
+            .is_some_and(|t| t.elapsed() < self.rebind_interval)
+        {
+            // Writes can fail while the gadget is coming back from the last rebind.
+            self.write_failed = false;
+            return;
+        }
+        if stuck {
+            warn!("The host didn't finish enumerating the gadget, re-enumerating it");
+        } else {
+            warn!("Writing reports failed, re-enumerating the gadget");
+        }
+        self.write_failed = false;
+        self.enumeration_started = None;
+        self.last_rebind = Some(Instant::now());
+        // Back off in case re-enumerating doesn't help, like with a host that never
+        // finishes enumerating the gadget.
+        self.rebind_interval = (self.rebind_interval * 2).min(MAX_REBIND_INTERVAL);
+        debug!(
+            "Not re-enumerating again for at least {:?}",
+            self.rebind_interval
+        );
+        if let Err(e) = self.gadget.rebind() {
+            error!("Failed to re-enumerate the gadget: {e:#}");
+            return;
+        }
+        // The gadget devices in use keep their slots; they just need their files
+        // reopened. Their reports are sent when the host configures the gadget again.
+        self.reopen_gadget_files();
+    }
+
+    /// Reopen every gadget device in use, after the gadget was rebound to its UDC.
+    /// Devices that can't be reopened yet are retried shortly.
+    fn reopen_gadget_files(&mut self) {
+        let mut failed = false;
+        for gadget_file in self.gadget_files_mut() {
+            if let Err(e) = gadget_file.reopen() {
+                debug!("{e:#}");
+                failed = true;
+            }
+        }
+        self.reopen_retry = failed.then(|| Instant::now() + REOPEN_RETRY_INTERVAL);
+    }
+
+    fn run(&mut self, term: Arc<AtomicBool>, reload: Arc<AtomicBool>) {
+        let mut control =
+            self.config
+                .control_socket()
+                .and_then(|path| match ControlServer::bind(path) {
+                    Ok(server) => Some(server),
+                    Err(e) => {
+                        error!("{e:#}");
+                        None
+                    }
+                });
+
+        // Open the evdev devices first, so that gilrs doesn't map them too.
+        self.open_evdev_devices();
+        self.open_network_inputs();
+        // Iterate over all connected gamepads
+        let gids = self
+            .gilrs
+            .gamepads()
+            .map(|(gid, _)| gid)
+            .collect::<Vec<_>>();
+        for gid in gids {
+            info!("Gamepad connected: {}", self.gilrs.gamepad(gid).name());
+            self.add_gilrs_gamepad(gid);
+        }
+        self.map_unmapped_controllers();
+
+        while !term.load(Ordering::Relaxed) {
+            self.poll_udc();
+            self.check_enumeration();
+            while let Some(Event { id: gid, event, .. }) = self.gilrs.next_event() {
+                self.cause = format!("gilrs {gid} {event:?}");
+                self.arrival = Some(Instant::now());
+                match event {
+                    EventType::Connected => {
+                        info!("Gamepad connected: {}", self.gilrs.gamepad(gid).name());
+                        let id = self.add_gilrs_gamepad(gid);
+                        if let Err(e) = self.map_controller(id) {
+                            error!("{e}");
+                        }
+                    }
+                    EventType::Disconnected => {
+                        info!("Gamepad disconnected: {gid}");
+                        if let Some(id) = self.gilrs_controller(gid) {
+                            self.remove_controller(id);
+                        }
+                    }
+                    EventType::Dropped => {}
+                    // If the gamepad we're providing doesn't have any analog buttons then we don't need
+                    // to handle ButtonChanged events, and since gilrs will generate one per press/release
+                    // that would cause us to write an extra report every time.
+                    EventType::ButtonChanged(_, _, _) if !G::ANALOG_BUTTONS => {}
+                    _ => {
+                        if let Some(id) = self.gilrs_controller(gid) {
+                            self.update_controller(id);
+                        }
+                    }
+                }
+            }
+            self.arrival = None;
+            self.poll_evdev_devices();
+            self.poll_network_inputs();
+            self.poll_output_reports();
+            self.poll_playbacks();
+            self.tick();
+            self.flush_reports();
+            if let Some(latency) = &mut self.latency {
+                latency.poll();
+            }
+            if Instant::now() >= self.evdev_retry {
+                self.open_evdev_devices();
+                self.evdev_retry = Instant::now() + EVDEV_RETRY_INTERVAL;
+            }
+            if let Some(control) = &mut control {
+                control.poll(|request| {
+                    self.cause = format!("control {request:?}");
+                    self.handle_request(request)
+                });
+            }
+            self.expire_injections();
+            if mem::take(&mut self.mappings_stale) {
+                if let Err(e) = self.refresh_mappings() {
+                    error!("Failed to refresh mappings: {e:#}");
+                }
+            }
+            if reload.swap(false, Ordering::Relaxed) {
+                info!("Got SIGHUP, reloading config");
+                self.cause = "reload".to_owned();
+                if let Err(e) = self.reload() {
+                    error!("Failed to reload config: {e:#}");
+                }
+            }
+        }
+    }
+
+    fn shutdown(mut self) {
+        if let Some(latency) = &mut self.latency {
+            latency.log_summary();
+        }
+        // Release any in-use gadgets before cleaning up for real.
+        let ids = self.controllers.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            self.unmap_controller(id);
+        }
+        for (_, gadget_file) in self.held_devices.drain() {
+            let _ = self.gadget.release_device(gadget_file);
+        }
+    }
+}
+
+fn create_and_run_gamepad_gadgets<G: HIDGamepad>(
+    config: Config,
+    config_path: Option<PathBuf>,
+    latency_mode: bool,
+    term: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+) -> Result<()> {
+    let mut daemon = Daemon::<G>::new(config, config_path)?;
+    if latency_mode {
+        daemon.latency = Some(LatencyTracker::new(
+            uhid::parse_id(G::VENDOR_ID)?,
+            uhid::parse_id(G::PRODUCT_ID)?,
+        ));
+    }
+    daemon.run(term, reload);
+    daemon.shutdown();
     Ok(())
 }
 
@@ -167,11 +1730,43 @@ fn main() -> Result<()> {
         .filter_level(LevelFilter::Info)
         .parse_default_env()
         .init();
+    let mut args = env::args_os().skip(1).peekable();
+    let learn_mode = args.next_if(|arg| arg == "learn").is_some();
+    let latency_mode = args.next_if(|arg| arg == "latency").is_some();
+    if args.next_if(|arg| arg == "emulate-host").is_some() {
+        let path = args
+            .next()
+            .map(PathBuf::from)
+            .ok_or_else(|| anyhow!("Usage: pizero-gadget-gamepads emulate-host <script>"))?;
+        let script = Script::load(&path)?;
+        HostEmulator::<HoriPokkenPad>::new()?.run(&script)?;
+        info!("All {} steps passed", script.step.len());
+        return Ok(());
+    }
+    let config_path = args
+        .next()
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|p| p.exists()));
+    let config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if learn_mode {
+        return learn(&config);
+    }
     let term = Arc::new(AtomicBool::new(false));
     signal_flag::register(SIGINT, Arc::clone(&term))?;
     signal_flag::register(SIGTERM, Arc::clone(&term))?;
     signal_flag::register(SIGQUIT, Arc::clone(&term))?;
-    create_and_run_gamepad_gadgets::<HoriPokkenPad>(term)?;
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_flag::register(SIGHUP, Arc::clone(&reload))?;
+    create_and_run_gamepad_gadgets::<HoriPokkenPad>(
+        config,
+        config_path,
+        latency_mode,
+        term,
+        reload,
+    )?;
     info!("Shutting down");
     Ok(())
 }
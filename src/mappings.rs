@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::input_device::InputDevice;
+use crate::raw_mapping;
+
+/// The environment variable SDL reads extra mappings from.
+pub const SDL_MAPPINGS_ENV: &str = "SDL_GAMECONTROLLERCONFIG";
+
+/// Where an SDL controller mapping came from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MappingOrigin {
+    /// The copy of SDL_GameControllerDB built into the binary.
+    GameControllerDb,
+    /// The `extra-mappings.txt` file built into the binary.
+    BuiltIn,
+    /// A mapping file on disk.
+    File(PathBuf),
+    /// Built from a `raw_mapping` entry in the config.
+    Raw,
+    /// The `SDL_GAMECONTROLLERCONFIG` environment variable.
+    Environment,
+}
+
+impl Display for MappingOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingOrigin::GameControllerDb => f.write_str("built-in gamecontrollerdb.txt"),
+            MappingOrigin::BuiltIn => f.write_str("built-in extra-mappings.txt"),
+            MappingOrigin::File(path) => write!(f, "{}", path.display()),
+            MappingOrigin::Raw => f.write_str("raw_mapping config"),
+            MappingOrigin::Environment => f.write_str(SDL_MAPPINGS_ENV),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    line: String,
+    origin: MappingOrigin,
+}
+
+impl Entry {
+    /// The controller name from the mapping line.
+    fn name(&self) -> Option<&str> {
+        self.line.split(',').nth(1)
+    }
+}
+
+/// SDL controller mappings collected from all of the places we load them from,
+/// de-duplicated by GUID.
+///
+/// Later sources take priority over earlier ones, in this order: the built-in copy
+/// of SDL_GameControllerDB, the built-in extra mappings, files in the configured
+/// mapping directory (in name order), the configured mapping file, and
+/// `SDL_GAMECONTROLLERCONFIG`. Mappings are built from the `raw_mapping` config
+/// for connected controllers that don't have one.
+///
+/// gilrs is given exactly these mappings and none of its own, so the mapping it
+/// uses for a controller is always the one here.
+#[derive(Debug, Default)]
+pub struct Mappings {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Mappings {
+    pub fn load(config: &Config) -> Result<Self> {
+        let mut mappings = Mappings::default();
+        mappings.insert(
+            include_str!("../gamecontrollerdb.txt"),
+            MappingOrigin::GameControllerDb,
+        );
+        mappings.insert(
+            include_str!("../extra-mappings.txt"),
+            MappingOrigin::BuiltIn,
+        );
+        if let Some(dir) = &config.mapping_dir {
+            let mut paths = fs::read_dir(dir)
+                .with_context(|| format!("Failed to read mapping directory: {dir:?}"))?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            paths.retain(|p| p.extension().is_some_and(|e| e == "txt"));
+            paths.sort();
+            for path in paths {
+                mappings.insert_file(&path)?;
+            }
+        }
+        if let Some(path) = &config.mapping_file {
+            // The mapping file is optional, it might not have been created yet.
+            if path.exists() {
+                mappings.insert_file(path)?;
+            } else {
+                debug!("Mapping file {path:?} doesn't exist");
+            }
+        }
+        if !config.raw_mapping.is_empty() {
+            for device in InputDevice::enumerate() {
+                let Some(raw) = config.raw_mapping_for(&device.name) else {
+                    continue;
+                };
+                if mappings.contains(&device.guid) {
+                    continue;
+                }
+                match raw_mapping::sdl_mapping(&device, raw) {
+                    Ok(line) => mappings.insert(&line, MappingOrigin::Raw),
+                    Err(e) => warn!("Can't build raw mapping for {}: {e:#}", device.name),
+                }
+            }
+        }
+        if let Ok(env_mappings) = env::var(SDL_MAPPINGS_ENV) {
+            mappings.insert(&env_mappings, MappingOrigin::Environment);
+        }
+        Ok(mappings)
+    }
+
+    /// Whether there's a mapping for the controller with GUID `guid`.
+    pub fn contains(&self, guid: &str) -> bool {
+        self.entries.contains_key(guid)
+    }
+
+    fn insert_file(&mut self, path: &Path) -> Result<()> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read mapping file: {path:?}"))?;
+        self.insert(&contents, MappingOrigin::File(path.to_owned()));
+        Ok(())
+    }
+
+    fn insert(&mut self, mappings: &str, origin: MappingOrigin) {
+        for line in mappings.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Mappings for other platforms use different GUIDs, and gilrs would ignore them anyway.
+            if let Some((_, platform)) = line.split_once("platform:") {
+                if platform.split(',').next() != Some("Linux") {
+                    continue;
+                }
+            }
+            let guid = match line.split_once(',') {
+                Some((guid, _)) => guid.to_ascii_lowercase(),
+                None => continue,
+            };
+            let entry = Entry {
+                line: line.to_owned(),
+                origin: origin.clone(),
+            };
+            if let Some(old) = self.entries.insert(guid.clone(), entry) {
+                debug!(
+                    "Mapping for {guid} from {origin} replaces the one from {}",
+                    old.origin
+                );
+            }
+        }
+    }
+
+    /// The mappings to give to gilrs, which should be built without its own bundled
+    /// or environment mappings.
+    pub fn gilrs_mappings(&self) -> String {
+        self.entries
+            .values()
+            .map(|e| format!("{}\n", e.line))
+            .collect()
+    }
+
+    /// Which source the mapping that gilrs is using for a controller came from.
+    ///
+    /// `guid` is the controller's GUID as formatted by `controller_key`, and `map_name`
+    /// is the name from the mapping that gilrs is using, if it's using one.
+    pub fn origin(&self, guid: &str, map_name: Option<&str>) -> Option<MappingOrigin> {
+        let map_name = map_name?;
+        let entry = self.entries.get(guid)?;
+        if entry.name() != Some(map_name) {
+            debug!("gilrs is using mapping {map_name:?} for {guid}, which isn't ours");
+            return None;
+        }
+        Some(entry.origin.clone())
+    }
+}
@@ -0,0 +1,405 @@
+use anyhow::{bail, Context, Result};
+use hmac_sha256::HMAC;
+use log::{debug, info};
+use scroll::{ctx::SizeWith, Pread, Pwrite, SizeWith, LE};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::NetworkConfig;
+use crate::controller_state::{ControllerState, BUTTONS};
+
+/// The first four bytes of every state packet.
+pub const MAGIC: [u8; 4] = *b"PZGP";
+/// The length of the HMAC-SHA256 signature that follows a packet when a secret is set.
+pub const SIGNATURE_LEN: usize = 32;
+
+/// A controller state sent over UDP. All fields are little-endian.
+///
+/// When a secret is configured the packet is followed by an HMAC-SHA256 of the
+/// packet, keyed with the secret.
+#[derive(Debug, Default, PartialEq, Pread, Pwrite, SizeWith)]
+pub struct StatePacket {
+    /// Always `MAGIC`.
+    pub magic: [u8; 4],
+    /// Incremented for every packet, so that late packets can be ignored.
+    pub sequence: u32,
+    /// When the sender sampled this state, in microseconds on the sender's clock.
+    ///
+    /// Signed packets must have increasing timestamps, even if the sender restarts,
+    /// so senders should use the time since the Unix epoch.
+    pub timestamp_us: u64,
+    /// One bit per button, in the order of `controller_state::BUTTONS`.
+    pub buttons: u32,
+    /// Stick axes from -32767 to 32767. Up is positive for Y.
+    pub left_x: i16,
+    pub left_y: i16,
+    pub right_x: i16,
+    pub right_y: i16,
+    /// Analog triggers from 0 to 255.
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+}
+
+fn axis_to_i16(value: f32) -> i16 {
+    (value.clamp(-1.0, 1.0) * 32767.0).round() as i16
+}
+
+fn axis_from_i16(value: i16) -> f32 {
+    (value as f32 / 32767.0).clamp(-1.0, 1.0)
+}
+
+impl StatePacket {
+    pub fn new(state: &ControllerState, sequence: u32, timestamp_us: u64) -> Self {
+        let buttons = BUTTONS
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| state.is_pressed(**b))
+            .fold(0, |bits, (i, _)| bits | 1 << i);
+        StatePacket {
+            magic: MAGIC,
+            sequence,
+            timestamp_us,
+            buttons,
+            left_x: axis_to_i16(state.left_stick.x),
+            left_y: axis_to_i16(state.left_stick.y),
+            right_x: axis_to_i16(state.right_stick.x),
+            right_y: axis_to_i16(state.right_stick.y),
+            left_trigger: (state.left_trigger.clamp(0.0, 1.0) * 255.0).round() as u8,
+            right_trigger: (state.right_trigger.clamp(0.0, 1.0) * 255.0).round() as u8,
+        }
+    }
+
+    pub fn state(&self) -> ControllerState {
+        let mut state = ControllerState::default();
+        for (i, button) in BUTTONS.iter().enumerate() {
+            state.set_pressed(*button, self.buttons & 1 << i != 0);
+        }
+        state.left_stick.x = axis_from_i16(self.left_x);
+        state.left_stick.y = axis_from_i16(self.left_y);
+        state.right_stick.x = axis_from_i16(self.right_x);
+        state.right_stick.y = axis_from_i16(self.right_y);
+        state.left_trigger = self.left_trigger as f32 / 255.0;
+        state.right_trigger = self.right_trigger as f32 / 255.0;
+        state
+    }
+
+    /// Serialize the packet, signing it if `secret` is set.
+    pub fn encode(&self, secret: Option<&[u8]>) -> Vec<u8> {
+        let mut buf = vec![0; StatePacket::size_with(&LE)];
+        // The buffer is exactly the right size, so this can't fail.
+        let _ = buf.pwrite_with(self, 0, LE);
+        if let Some(secret) = secret {
+            let signature = HMAC::mac(&buf, secret);
+            buf.extend_from_slice(&signature);
+        }
+        buf
+    }
+
+    /// Parse a packet, checking its signature if `secret` is set.
+    pub fn decode(buf: &[u8], secret: Option<&[u8]>) -> Result<Self> {
+        let size = StatePacket::size_with(&LE);
+        if let Some(secret) = secret {
+            if buf.len() != size + SIGNATURE_LEN {
+                bail!("Expected a signed packet of {} bytes", size + SIGNATURE_LEN);
+            }
+            let (data, signature) = buf.split_at(size);
+            if !HMAC::verify(data, secret, signature.try_into()?) {
+                bail!("Bad signature");
+            }
+        }
+        let packet = buf.pread_with::<StatePacket>(0, LE)?;
+        if packet.magic != MAGIC {
+            bail!("Bad magic");
+        }
+        Ok(packet)
+    }
+}
+
+/// How far `timestamp_us`, in microseconds since the Unix epoch, is from now.
+fn timestamp_age(timestamp_us: u64) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.abs_diff(Duration::from_micros(timestamp_us))
+}
+
+/// What happened when a network input was polled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetworkEvent {
+    Nothing,
+    /// The state changed.
+    Changed,
+    /// Packets started arriving.
+    Connected,
+    /// Packets stopped arriving for long enough to give up the gadget device.
+    Disconnected,
+}
+
+/// A controller driven by state packets sent over UDP.
+pub struct NetworkInput {
+    socket: UdpSocket,
+    pub name: String,
+    pub listen: SocketAddr,
+    secret: Option<Vec<u8>>,
+    /// How far a signed packet's timestamp can be from our clock.
+    max_age: Option<Duration>,
+    /// How long to hold the last state for when packets stop.
+    timeout: Duration,
+    /// How long after packets stop to count as disconnected.
+    disconnect_after: Duration,
+    /// Whether packets are arriving, or have arrived recently enough to keep the gadget device.
+    active: bool,
+    /// Where the last packet came from.
+    peer: Option<SocketAddr>,
+    sequence: u32,
+    /// The timestamp of the last packet that was used.
+    timestamp_us: u64,
+    last_packet: Option<Instant>,
+    state: ControllerState,
+    /// Packets that never arrived, or arrived too late to be used.
+    lost: u64,
+}
+
+impl NetworkInput {
+    /// Start listening as described by `config`.
+    pub fn open(config: &NetworkConfig) -> Result<Self> {
+        let socket = UdpSocket::bind(config.listen)
+            .with_context(|| format!("Failed to listen on {}", config.listen))?;
+        socket.set_nonblocking(true)?;
+        info!("Listening for {} on {}", config.name, config.listen);
+        Ok(NetworkInput {
+            socket,
+            name: config.name.clone(),
+            listen: config.listen,
+            secret: config.secret.as_ref().map(|s| s.as_bytes().to_vec()),
+            max_age: config.max_age_ms.map(Duration::from_millis),
+            timeout: Duration::from_millis(config.timeout_ms),
+            disconnect_after: Duration::from_millis(config.disconnect_ms),
+            active: false,
+            peer: None,
+            sequence: 0,
+            timestamp_us: 0,
+            last_packet: None,
+            state: ControllerState::default(),
+            lost: 0,
+        })
+    }
+
+    /// Whether the input is active, and should be mapped to a gadget device.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn state(&self) -> ControllerState {
+        self.state.clone()
+    }
+
+    /// Handle a packet. Returns whether it was used.
+    fn receive(&mut self, buf: &[u8], addr: SocketAddr, now: Instant) -> bool {
+        let packet = match StatePacket::decode(buf, self.secret.as_deref()) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Ignoring packet from {addr}: {e:#}");
+                return false;
+            }
+        };
+        let ahead = packet.sequence.wrapping_sub(self.sequence) as i32;
+        if self.secret.is_some() {
+            // Anyone can resend a signed packet, so only ever move forward. A sender
+            // that restarts starts a new sequence, but its timestamps keep going up.
+            if let Some(max_age) = self.max_age {
+                if timestamp_age(packet.timestamp_us) > max_age {
+                    debug!("Dropping stale packet {} from {addr}", packet.sequence);
+                    return false;
+                }
+            }
+            if self.last_packet.is_some() {
+                if packet.timestamp_us <= self.timestamp_us {
+                    debug!("Dropping late packet {} from {addr}", packet.sequence);
+                    return false;
+                }
+                if ahead <= 0 {
+                    info!("Sender for {} restarted its sequence numbers", self.name);
+                } else {
+                    self.lost += ahead as u64 - 1;
+                }
+            }
+        } else {
+            // After a timeout accept any sequence number, in case the sender restarted.
+            let timed_out = self.last_packet.is_none_or(|t| now - t > self.timeout);
+            if !timed_out {
+                if ahead <= 0 {
+                    debug!("Dropping late packet {} from {addr}", packet.sequence);
+                    return false;
+                }
+                self.lost += ahead as u64 - 1;
+            }
+        }
+        if self.peer != Some(addr) {
+            info!("{} is being driven from {addr}", self.name);
+            self.peer = Some(addr);
+        }
+        self.sequence = packet.sequence;
+        self.timestamp_us = packet.timestamp_us;
+        self.last_packet = Some(now);
+        self.state = packet.state();
+        true
+    }
+
+    /// Read any pending packets, and release the inputs if they've stopped.
+    pub fn poll(&mut self) -> NetworkEvent {
+        let now = Instant::now();
+        let mut event = NetworkEvent::Nothing;
+        let mut buf = [0; 256];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, addr)) => {
+                    if !self.receive(&buf[..len], addr, now) {
+                        continue;
+                    }
+                    if !self.active {
+                        self.active = true;
+                        event = NetworkEvent::Connected;
+                    } else if event == NetworkEvent::Nothing {
+                        event = NetworkEvent::Changed;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("Error receiving on {}: {e}", self.listen);
+                    break;
+                }
+            }
+        }
+        let Some(last_packet) = self.last_packet else {
+            return event;
+        };
+        let idle = now - last_packet;
+        if self.active && idle > self.disconnect_after {
+            info!(
+                "No packets for {} for {:?} ({} lost in total)",
+                self.name, idle, self.lost
+            );
+            self.active = false;
+            return NetworkEvent::Disconnected;
+        }
+        if idle > self.timeout && self.state != ControllerState::default() {
+            debug!(
+                "No packets for {} for {idle:?}, releasing inputs",
+                self.name
+            );
+            self.state = ControllerState::default();
+            if event == NetworkEvent::Nothing {
+                event = NetworkEvent::Changed;
+            }
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid_gadget::poll_readable;
+    use gilrs::Button;
+    use std::thread;
+
+    const SECRET: &[u8] = b"test secret";
+
+    fn open(timeout_ms: u64, max_age_ms: Option<u64>) -> (NetworkInput, UdpSocket) {
+        let config = NetworkConfig {
+            name: "test".to_owned(),
+            listen: "127.0.0.1:0".parse().unwrap(),
+            secret: Some(String::from_utf8(SECRET.to_vec()).unwrap()),
+            max_age_ms,
+            timeout_ms,
+            disconnect_ms: 5000,
+        };
+        let input = NetworkInput::open(&config).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.connect(input.socket.local_addr().unwrap()).unwrap();
+        (input, sender)
+    }
+
+    fn now_us() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64
+    }
+
+    fn pressed(button: Button) -> ControllerState {
+        let mut state = ControllerState::default();
+        state.press(&[button]);
+        state
+    }
+
+    /// Send `packet` and poll the input once it has arrived.
+    fn send(input: &mut NetworkInput, sender: &UdpSocket, packet: &[u8]) -> NetworkEvent {
+        sender.send(packet).unwrap();
+        assert!(poll_readable(&input.socket, Duration::from_secs(5)).unwrap());
+        input.poll()
+    }
+
+    #[test]
+    fn signed_packets() {
+        let (mut input, sender) = open(5000, None);
+        let start = now_us();
+        let south = StatePacket::new(&pressed(Button::South), 10, start).encode(Some(SECRET));
+        assert_eq!(send(&mut input, &sender, &south), NetworkEvent::Connected);
+        assert!(input.state().is_pressed(Button::South));
+
+        // Not signed, or signed with the wrong secret.
+        let east = StatePacket::new(&pressed(Button::East), 11, start + 1);
+        assert_eq!(
+            send(&mut input, &sender, &east.encode(None)),
+            NetworkEvent::Nothing
+        );
+        let mut bad_mac = east.encode(Some(SECRET));
+        *bad_mac.last_mut().unwrap() ^= 1;
+        assert_eq!(send(&mut input, &sender, &bad_mac), NetworkEvent::Nothing);
+        assert!(input.state().is_pressed(Button::South));
+
+        // Replayed and late packets.
+        assert_eq!(send(&mut input, &sender, &south), NetworkEvent::Nothing);
+        let late = StatePacket::new(&pressed(Button::North), 9, start - 1).encode(Some(SECRET));
+        assert_eq!(send(&mut input, &sender, &late), NetworkEvent::Nothing);
+
+        let east = east.encode(Some(SECRET));
+        assert_eq!(send(&mut input, &sender, &east), NetworkEvent::Changed);
+        assert!(input.state().is_pressed(Button::East));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let (mut input, sender) = open(50, None);
+        let start = now_us();
+        let south = StatePacket::new(&pressed(Button::South), 1, start).encode(Some(SECRET));
+        assert_eq!(send(&mut input, &sender, &south), NetworkEvent::Connected);
+
+        // The inputs are released once packets stop for longer than the timeout.
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(input.poll(), NetworkEvent::Changed);
+        assert_eq!(input.state(), ControllerState::default());
+
+        // The timeout doesn't let an old packet back in.
+        assert_eq!(send(&mut input, &sender, &south), NetworkEvent::Nothing);
+        assert_eq!(input.state(), ControllerState::default());
+
+        // A sender that restarts starts its sequence again, with a later timestamp.
+        let east = StatePacket::new(&pressed(Button::East), 0, now_us()).encode(Some(SECRET));
+        assert_eq!(send(&mut input, &sender, &east), NetworkEvent::Changed);
+        assert!(input.state().is_pressed(Button::East));
+    }
+
+    #[test]
+    fn stale_packets() {
+        let (mut input, sender) = open(5000, Some(1000));
+        let old = now_us() - 5_000_000;
+        let stale = StatePacket::new(&pressed(Button::South), 1, old).encode(Some(SECRET));
+        assert_eq!(send(&mut input, &sender, &stale), NetworkEvent::Nothing);
+        let fresh = StatePacket::new(&pressed(Button::South), 2, now_us()).encode(Some(SECRET));
+        assert_eq!(send(&mut input, &sender, &fresh), NetworkEvent::Connected);
+    }
+}
@@ -0,0 +1,253 @@
+use anyhow::{anyhow, bail, Context, Result};
+use gilrs::Button;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::controller_state::{ControllerState, Stick};
+use crate::recorder::{self, RecordedReport};
+
+/// How long a TAS frame lasts. The Switch runs at 60 frames per second.
+pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// nx-TAS button names, and the buttons they are in gilrs' layout.
+const TAS_BUTTONS: &[(&str, Button)] = &[
+    ("KEY_A", Button::East),
+    ("KEY_B", Button::South),
+    ("KEY_X", Button::North),
+    ("KEY_Y", Button::West),
+    ("KEY_L", Button::LeftTrigger),
+    ("KEY_R", Button::RightTrigger),
+    ("KEY_ZL", Button::LeftTrigger2),
+    ("KEY_ZR", Button::RightTrigger2),
+    ("KEY_PLUS", Button::Start),
+    ("KEY_MINUS", Button::Select),
+    ("KEY_HOME", Button::Mode),
+    ("KEY_LSTICK", Button::LeftThumb),
+    ("KEY_RSTICK", Button::RightThumb),
+    ("KEY_DUP", Button::DPadUp),
+    ("KEY_DDOWN", Button::DPadDown),
+    ("KEY_DLEFT", Button::DPadLeft),
+    ("KEY_DRIGHT", Button::DPadRight),
+];
+
+fn parse_stick(s: &str) -> Result<Stick> {
+    let (x, y) = s
+        .split_once(';')
+        .ok_or_else(|| anyhow!("Bad stick position: {s}"))?;
+    let axis = |v: &str| -> Result<f32> {
+        let v = v
+            .parse::<i32>()
+            .map_err(|_| anyhow!("Bad stick position: {s}"))?;
+        Ok((v as f32 / 32767.0).clamp(-1.0, 1.0))
+    };
+    Ok(Stick {
+        x: axis(x)?,
+        y: axis(y)?,
+    })
+}
+
+/// Parse one line of an nx-TAS script, like `12 KEY_A;KEY_DUP 0;32767 0;0`.
+fn parse_tas_line(line: &str) -> Result<(u64, ControllerState)> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let [frame, buttons, left, right] = fields.as_slice() else {
+        bail!("Expected a frame number, buttons and two stick positions");
+    };
+    let frame = frame
+        .parse()
+        .map_err(|_| anyhow!("Bad frame number: {frame}"))?;
+    let mut state = ControllerState::default();
+    if *buttons != "NONE" {
+        for name in buttons.split(';') {
+            let button = TAS_BUTTONS
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, b)| *b)
+                .ok_or_else(|| anyhow!("Unknown button: {name}"))?;
+            state.set_pressed(button, true);
+        }
+    }
+    state.left_stick = parse_stick(left)?;
+    state.right_stick = parse_stick(right)?;
+    Ok((frame, state))
+}
+
+/// Parse an nx-TAS script. Each line is the input for one frame, and frames that
+/// aren't listed have nothing pressed.
+pub fn parse_tas(contents: &str) -> Result<Vec<(u64, ControllerState)>> {
+    let mut frames: Vec<(u64, ControllerState)> = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (frame, state) = parse_tas_line(line).with_context(|| format!("Line {}", i + 1))?;
+        if frames.last().is_some_and(|(last, _)| *last >= frame) {
+            bail!("Line {}: frame {frame} is out of order", i + 1);
+        }
+        frames.push((frame, state));
+    }
+    Ok(frames)
+}
+
+/// What a playback is sending to its gadget device.
+#[derive(Clone, Debug)]
+pub enum Frame {
+    /// A controller state, from a TAS script.
+    State(ControllerState),
+    /// A report exactly as it was recorded.
+    Report(Vec<u8>),
+}
+
+enum Frames {
+    Tas(Vec<(u64, ControllerState)>),
+    Recording(Vec<RecordedReport>),
+}
+
+/// Plays a TAS script or a recording to a gadget device.
+///
+/// TAS scripts send a report every frame, whether anything changed or not.
+/// Recordings send each report that was recorded for the gadget device, at the
+/// time it was originally sent. Every frame or report is sent, in order, even if
+/// it's late.
+pub struct Playback {
+    pub path: PathBuf,
+    frames: Frames,
+    start: Instant,
+    /// For TAS scripts the next frame number, and for recordings the index of the
+    /// next report.
+    next: u64,
+    current: Frame,
+}
+
+impl Playback {
+    /// Load a TAS script or a recording to play to gadget device `slot`, which takes
+    /// reports of `report_size` bytes.
+    pub fn load(path: &Path, slot: usize, report_size: usize) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        let frames = if contents.starts_with('#') {
+            let mut reports = recorder::load(path)?
+                .into_iter()
+                .filter(|r| r.slot == slot)
+                .collect::<Vec<_>>();
+            if let Some(r) = reports.iter().find(|r| r.report.len() != report_size) {
+                bail!(
+                    "{path:?} has a {} byte report, but this gamepad's reports are {report_size} bytes",
+                    r.report.len()
+                );
+            }
+            if reports.is_empty() {
+                bail!("{path:?} has no reports for gadget device {slot}");
+            }
+            // Start playing straight away, rather than after however long the
+            // recording ran before this gadget device was used.
+            let first = reports[0].time;
+            for report in &mut reports {
+                report.time -= first;
+            }
+            Frames::Recording(reports)
+        } else {
+            Frames::Tas(parse_tas(&contents).with_context(|| format!("Failed to parse {path:?}"))?)
+        };
+        let current = match &frames {
+            Frames::Tas(_) => Frame::State(ControllerState::default()),
+            Frames::Recording(reports) => Frame::Report(reports[0].report.clone()),
+        };
+        Ok(Playback {
+            path: path.to_owned(),
+            frames,
+            start: Instant::now(),
+            next: 0,
+            current,
+        })
+    }
+
+    /// Move the playback on by one frame, if the next one is due by `now`. Returns
+    /// whether there's a new report to send.
+    ///
+    /// Frames are never skipped, so if the playback has fallen behind this keeps
+    /// returning `true` until it's caught up.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let elapsed = now - self.start;
+        match &self.frames {
+            Frames::Tas(frames) => {
+                if self.next as u128 * FRAME.as_nanos() > elapsed.as_nanos() {
+                    return false;
+                }
+                let state = frames
+                    .binary_search_by_key(&self.next, |(f, _)| *f)
+                    .map(|i| frames[i].1.clone())
+                    .unwrap_or_default();
+                self.current = Frame::State(state);
+            }
+            Frames::Recording(reports) => match reports.get(self.next as usize) {
+                Some(report) if report.time <= elapsed => {
+                    self.current = Frame::Report(report.report.clone());
+                }
+                _ => return false,
+            },
+        }
+        self.next += 1;
+        true
+    }
+
+    /// The report that's currently being sent.
+    pub fn frame(&self) -> &Frame {
+        &self.current
+    }
+
+    /// Whether everything has been played. TAS scripts finish after a frame with
+    /// nothing pressed has been sent following the last frame in the script.
+    pub fn is_finished(&self) -> bool {
+        match &self.frames {
+            Frames::Tas(frames) => frames.last().is_none_or(|(last, _)| self.next > last + 1),
+            Frames::Recording(reports) => self.next as usize >= reports.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressed(button: Button) -> ControllerState {
+        let mut state = ControllerState::default();
+        state.press(&[button]);
+        state
+    }
+
+    fn state(playback: &Playback) -> ControllerState {
+        match playback.frame() {
+            Frame::State(state) => state.clone(),
+            Frame::Report(_) => panic!("TAS playback sent a raw report"),
+        }
+    }
+
+    #[test]
+    fn late_frames_are_played_in_order() {
+        let tas = parse_tas("0 KEY_A 0;0 0;0\n2 KEY_B 0;0 0;0\n").unwrap();
+        let now = Instant::now();
+        let mut playback = Playback {
+            path: PathBuf::from("test.txt"),
+            frames: Frames::Tas(tas),
+            // Start a few frames ago, as if the daemon had been busy.
+            start: now - FRAME * 3 - FRAME / 2,
+            next: 0,
+            current: Frame::State(ControllerState::default()),
+        };
+        let mut played = vec![];
+        while playback.poll(now) {
+            played.push(state(&playback));
+        }
+        let expected = [
+            pressed(Button::East),
+            ControllerState::default(),
+            pressed(Button::South),
+            ControllerState::default(),
+        ];
+        assert_eq!(played, expected);
+        assert!(playback.is_finished());
+        // The next frame isn't due yet.
+        assert!(!playback.poll(now));
+    }
+}
@@ -0,0 +1,475 @@
+use anyhow::{anyhow, bail, Result};
+use evdev::{AbsoluteAxisType, Key};
+use gilrs::{Axis, Button};
+use log::info;
+use std::collections::HashMap;
+
+use crate::config::RawMapping;
+use crate::input_device::{InputDevice, EV_ABS, EV_KEY};
+
+/// The SDL mapping field for each gadget button.
+const BUTTON_FIELDS: &[(Button, &str)] = &[
+    (Button::South, "a"),
+    (Button::East, "b"),
+    (Button::West, "x"),
+    (Button::North, "y"),
+    (Button::Select, "back"),
+    (Button::Start, "start"),
+    (Button::Mode, "guide"),
+    (Button::LeftTrigger, "leftshoulder"),
+    (Button::RightTrigger, "rightshoulder"),
+    (Button::LeftTrigger2, "lefttrigger"),
+    (Button::RightTrigger2, "righttrigger"),
+    (Button::LeftThumb, "leftstick"),
+    (Button::RightThumb, "rightstick"),
+    (Button::DPadUp, "dpup"),
+    (Button::DPadDown, "dpdown"),
+    (Button::DPadLeft, "dpleft"),
+    (Button::DPadRight, "dpright"),
+];
+
+/// The SDL mapping field for each gadget axis.
+const AXIS_FIELDS: &[(Axis, &str)] = &[
+    (Axis::LeftStickX, "leftx"),
+    (Axis::LeftStickY, "lefty"),
+    (Axis::RightStickX, "rightx"),
+    (Axis::RightStickY, "righty"),
+];
+
+/// Buttons with codes that say what they are.
+const GAMEPAD_BUTTONS: &[(Button, Key)] = &[
+    (Button::South, Key::BTN_SOUTH),
+    (Button::East, Key::BTN_EAST),
+    (Button::West, Key::BTN_WEST),
+    (Button::North, Key::BTN_NORTH),
+    (Button::Select, Key::BTN_SELECT),
+    (Button::Start, Key::BTN_START),
+    (Button::Mode, Key::BTN_MODE),
+    (Button::LeftTrigger, Key::BTN_TL),
+    (Button::RightTrigger, Key::BTN_TR),
+    (Button::LeftTrigger2, Key::BTN_TL2),
+    (Button::RightTrigger2, Key::BTN_TR2),
+    (Button::LeftThumb, Key::BTN_THUMBL),
+    (Button::RightThumb, Key::BTN_THUMBR),
+    (Button::DPadUp, Key::BTN_DPAD_UP),
+    (Button::DPadDown, Key::BTN_DPAD_DOWN),
+    (Button::DPadLeft, Key::BTN_DPAD_LEFT),
+    (Button::DPadRight, Key::BTN_DPAD_RIGHT),
+];
+
+/// The order that generic HID joysticks (`BTN_TRIGGER` onwards) usually number
+/// their buttons in, which follows the PlayStation layout.
+const JOYSTICK_BUTTONS: &[Button] = &[
+    Button::North,
+    Button::East,
+    Button::South,
+    Button::West,
+    Button::LeftTrigger,
+    Button::RightTrigger,
+    Button::LeftTrigger2,
+    Button::RightTrigger2,
+    Button::Select,
+    Button::Start,
+    Button::LeftThumb,
+    Button::RightThumb,
+    Button::Mode,
+];
+
+const HAT0X: u16 = AbsoluteAxisType::ABS_HAT0X.0;
+const HAT0Y: u16 = AbsoluteAxisType::ABS_HAT0Y.0;
+
+/// Parse an evdev code name like `BTN_SOUTH` or `ABS_X` into its event type and code.
+pub fn parse_evdev_code(name: &str) -> Result<(u16, u16)> {
+    let unknown = |_| anyhow!("Unknown evdev code: {name}");
+    if name.starts_with("ABS_") {
+        Ok((EV_ABS, name.parse::<AbsoluteAxisType>().map_err(unknown)?.0))
+    } else {
+        Ok((EV_KEY, name.parse::<Key>().map_err(unknown)?.code()))
+    }
+}
+
+fn code_name(kind: u16, code: u16) -> String {
+    match kind {
+        EV_ABS => format!("{:?}", AbsoluteAxisType(code)),
+        _ => format!("{:?}", Key::new(code)),
+    }
+}
+
+/// Which evdev code a gadget button or axis is bound to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Binding {
+    /// An `EV_KEY` code.
+    Key(u16),
+    /// An `EV_ABS` code.
+    Abs(u16),
+    /// One direction of a hat switch axis.
+    Hat { code: u16, positive: bool },
+}
+
+impl Binding {
+    fn from_code(kind: u16, code: u16) -> Binding {
+        match kind {
+            EV_ABS => Binding::Abs(code),
+            _ => Binding::Key(code),
+        }
+    }
+
+    /// Bind `button` to an evdev code, which is a hat direction if `button` is a d-pad
+    /// direction and `code` is a hat axis.
+    fn for_button(button: Button, kind: u16, code: u16) -> Result<Binding> {
+        let hats = AbsoluteAxisType::ABS_HAT0X.0..=AbsoluteAxisType::ABS_HAT3Y.0;
+        if kind != EV_ABS || !hats.contains(&code) {
+            return Ok(Binding::from_code(kind, code));
+        }
+        let y_axis = (code - hats.start()) % 2 == 1;
+        let positive = match (button, y_axis) {
+            (Button::DPadUp, true) | (Button::DPadLeft, false) => false,
+            (Button::DPadDown, true) | (Button::DPadRight, false) => true,
+            _ => bail!("{button:?} can't be bound to {}", code_name(kind, code)),
+        };
+        Ok(Binding::Hat { code, positive })
+    }
+
+    fn code(&self) -> (u16, u16) {
+        match *self {
+            Binding::Key(code) => (EV_KEY, code),
+            Binding::Abs(code) | Binding::Hat { code, .. } => (EV_ABS, code),
+        }
+    }
+
+    /// The SDL mapping value for this binding on `device`.
+    fn sdl(&self, device: &InputDevice) -> Result<String> {
+        let (kind, code) = self.code();
+        match *self {
+            // gilrs only supports the first hat, and only for the d-pad.
+            Binding::Hat { code, positive } => {
+                let direction = match (code, positive) {
+                    (HAT0Y, false) => 1,
+                    (HAT0X, true) => 2,
+                    (HAT0Y, true) => 4,
+                    (HAT0X, false) => 8,
+                    _ => bail!("Only ABS_HAT0X and ABS_HAT0Y can be used in SDL mappings"),
+                };
+                Ok(format!("h0.{direction}"))
+            }
+            _ => device
+                .binding(kind, code)
+                .ok_or_else(|| anyhow!("Device doesn't have {}", code_name(kind, code))),
+        }
+    }
+}
+
+/// The evdev codes that a device's buttons and axes are bound to.
+#[derive(Debug, Default)]
+pub struct Bindings {
+    pub buttons: Vec<(Button, Binding)>,
+    pub axes: Vec<(Axis, Binding)>,
+}
+
+impl Bindings {
+    fn is_bound(&self, button: Option<Button>, axis: Option<Axis>, binding: Binding) -> bool {
+        self.buttons
+            .iter()
+            .any(|(b, bb)| Some(*b) == button || *bb == binding)
+            || self
+                .axes
+                .iter()
+                .any(|(a, ab)| Some(*a) == axis || *ab == binding)
+    }
+
+    /// Bind `button` to a guessed code, if neither has been bound yet.
+    fn guess_button(&mut self, device: &InputDevice, button: Button, kind: u16, code: u16) {
+        let Ok(binding) = Binding::for_button(button, kind, code) else {
+            return;
+        };
+        if !device.has(kind, code) || self.is_bound(Some(button), None, binding) {
+            return;
+        }
+        info!(
+            "Guessed {button:?} = {} for {}",
+            code_name(kind, code),
+            device.name
+        );
+        self.buttons.push((button, binding));
+    }
+
+    /// Bind `axis` to a guessed code, if neither has been bound yet.
+    fn guess_axis(&mut self, device: &InputDevice, axis: Axis, code: u16) {
+        let binding = Binding::Abs(code);
+        if !device.has(EV_ABS, code) || self.is_bound(None, Some(axis), binding) {
+            return;
+        }
+        info!(
+            "Guessed {axis:?} = {} for {}",
+            code_name(EV_ABS, code),
+            device.name
+        );
+        self.axes.push((axis, binding));
+    }
+
+    fn guess(&mut self, device: &InputDevice) {
+        for (button, key) in GAMEPAD_BUTTONS {
+            self.guess_button(device, *button, EV_KEY, key.code());
+        }
+        let joystick_buttons = device
+            .buttons
+            .iter()
+            .filter(|c| (Key::BTN_TRIGGER.code()..=Key::BTN_DEAD.code()).contains(*c));
+        for (button, code) in JOYSTICK_BUTTONS.iter().zip(joystick_buttons) {
+            self.guess_button(device, *button, EV_KEY, *code);
+        }
+        self.guess_button(device, Button::DPadUp, EV_ABS, HAT0Y);
+        self.guess_button(device, Button::DPadDown, EV_ABS, HAT0Y);
+        self.guess_button(device, Button::DPadLeft, EV_ABS, HAT0X);
+        self.guess_button(device, Button::DPadRight, EV_ABS, HAT0X);
+
+        self.guess_axis(device, Axis::LeftStickX, AbsoluteAxisType::ABS_X.0);
+        self.guess_axis(device, Axis::LeftStickY, AbsoluteAxisType::ABS_Y.0);
+        // Xbox style controllers put the right stick on RX/RY and the triggers on
+        // Z/RZ, generic HID ones put the right stick on Z/RZ.
+        let (rx, ry) = if device.has(EV_ABS, AbsoluteAxisType::ABS_RX.0)
+            && device.has(EV_ABS, AbsoluteAxisType::ABS_RY.0)
+        {
+            (AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY)
+        } else {
+            (AbsoluteAxisType::ABS_Z, AbsoluteAxisType::ABS_RZ)
+        };
+        self.guess_axis(device, Axis::RightStickX, rx.0);
+        self.guess_axis(device, Axis::RightStickY, ry.0);
+        self.guess_button(
+            device,
+            Button::LeftTrigger2,
+            EV_ABS,
+            AbsoluteAxisType::ABS_Z.0,
+        );
+        self.guess_button(
+            device,
+            Button::RightTrigger2,
+            EV_ABS,
+            AbsoluteAxisType::ABS_RZ.0,
+        );
+    }
+
+    /// Work out the bindings for `device` from a map of gadget buttons and axes to evdev
+    /// code names, guessing the rest if `guess` is set.
+    pub fn new(
+        device: &InputDevice,
+        guess: bool,
+        buttons: &HashMap<Button, String>,
+        axes: &HashMap<Axis, String>,
+    ) -> Result<Self> {
+        let mut bindings = Bindings::default();
+        for (button, name) in buttons {
+            let (kind, code) = parse_evdev_code(name)?;
+            if !device.has(kind, code) {
+                bail!("Device doesn't have {name}");
+            }
+            let binding = Binding::for_button(*button, kind, code)?;
+            bindings.buttons.push((*button, binding));
+        }
+        for (axis, name) in axes {
+            let (kind, code) = parse_evdev_code(name)?;
+            if !device.has(kind, code) {
+                bail!("Device doesn't have {name}");
+            }
+            bindings.axes.push((*axis, Binding::from_code(kind, code)));
+        }
+        if guess {
+            bindings.guess(device);
+        }
+        if bindings.buttons.is_empty() && bindings.axes.is_empty() {
+            bail!("Nothing to map for {}", device.name);
+        }
+        Ok(bindings)
+    }
+}
+
+/// Build an SDL mapping line for `device` from its raw mapping configuration.
+pub fn sdl_mapping(device: &InputDevice, raw: &RawMapping) -> Result<String> {
+    let bindings = Bindings::new(device, raw.guess, &raw.buttons, &raw.axes)?;
+    let mut fields = String::new();
+    for (button, binding) in &bindings.buttons {
+        let field = BUTTON_FIELDS
+            .iter()
+            .find(|(b, _)| b == button)
+            .map(|(_, f)| f)
+            .ok_or_else(|| anyhow!("{button:?} can't be used in SDL mappings"))?;
+        fields.push_str(&format!("{field}:{},", binding.sdl(device)?));
+    }
+    for (axis, binding) in &bindings.axes {
+        let field = AXIS_FIELDS
+            .iter()
+            .find(|(a, _)| a == axis)
+            .map(|(_, f)| f)
+            .ok_or_else(|| anyhow!("{axis:?} can't be used in SDL mappings"))?;
+        fields.push_str(&format!("{field}:{},", binding.sdl(device)?));
+    }
+    Ok(device.sdl_mapping(&fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const GUID: &str = "03000000790000000600000010010000";
+
+    fn device(buttons: &[Key], axes: &[AbsoluteAxisType]) -> InputDevice {
+        InputDevice {
+            path: PathBuf::from("/dev/input/event0"),
+            name: "Test Pad".to_owned(),
+            guid: GUID.to_owned(),
+            buttons: buttons.iter().map(|k| k.code()).collect(),
+            axes: axes.iter().map(|a| a.0).collect(),
+        }
+    }
+
+    fn raw(guess: bool, buttons: &[(Button, &str)], axes: &[(Axis, &str)]) -> RawMapping {
+        RawMapping {
+            name: "Test Pad".to_owned(),
+            guess,
+            buttons: buttons.iter().map(|(b, n)| (*b, n.to_string())).collect(),
+            axes: axes.iter().map(|(a, n)| (*a, n.to_string())).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_codes() {
+        assert_eq!(parse_evdev_code("BTN_SOUTH").unwrap(), (EV_KEY, 0x130));
+        assert_eq!(parse_evdev_code("BTN_TRIGGER").unwrap(), (EV_KEY, 0x120));
+        assert_eq!(parse_evdev_code("ABS_X").unwrap(), (EV_ABS, 0x00));
+        assert_eq!(parse_evdev_code("ABS_HAT0Y").unwrap(), (EV_ABS, 0x11));
+        assert!(parse_evdev_code("BTN_NOPE").is_err());
+        assert!(parse_evdev_code("ABS_NOPE").is_err());
+    }
+
+    #[test]
+    fn guess_gamepad() {
+        // An Xbox style pad, with codes that say what they are and the triggers on Z/RZ.
+        let device = device(
+            &[
+                Key::BTN_SOUTH,
+                Key::BTN_EAST,
+                Key::BTN_NORTH,
+                Key::BTN_WEST,
+                Key::BTN_TL,
+                Key::BTN_TR,
+                Key::BTN_SELECT,
+                Key::BTN_START,
+                Key::BTN_MODE,
+                Key::BTN_THUMBL,
+                Key::BTN_THUMBR,
+            ],
+            &[
+                AbsoluteAxisType::ABS_X,
+                AbsoluteAxisType::ABS_Y,
+                AbsoluteAxisType::ABS_Z,
+                AbsoluteAxisType::ABS_RX,
+                AbsoluteAxisType::ABS_RY,
+                AbsoluteAxisType::ABS_RZ,
+                AbsoluteAxisType::ABS_HAT0X,
+                AbsoluteAxisType::ABS_HAT0Y,
+            ],
+        );
+        assert_eq!(
+            sdl_mapping(&device, &raw(true, &[], &[])).unwrap(),
+            format!(
+                "{GUID},Test Pad,a:b0,b:b1,x:b3,y:b2,back:b6,start:b7,guide:b8,\
+                 leftshoulder:b4,rightshoulder:b5,leftstick:b9,rightstick:b10,\
+                 dpup:h0.1,dpdown:h0.4,dpleft:h0.8,dpright:h0.2,\
+                 lefttrigger:a2,righttrigger:a5,\
+                 leftx:a0,lefty:a1,rightx:a3,righty:a4,platform:Linux,"
+            )
+        );
+    }
+
+    #[test]
+    fn guess_joystick() {
+        // A generic HID pad, with numbered buttons, the right stick on Z/RZ and a hat.
+        let buttons = (Key::BTN_TRIGGER.code()..=Key::BTN_BASE6.code())
+            .map(Key::new)
+            .collect::<Vec<_>>();
+        let device = device(
+            &buttons,
+            &[
+                AbsoluteAxisType::ABS_X,
+                AbsoluteAxisType::ABS_Y,
+                AbsoluteAxisType::ABS_Z,
+                AbsoluteAxisType::ABS_RZ,
+                AbsoluteAxisType::ABS_HAT0X,
+                AbsoluteAxisType::ABS_HAT0Y,
+            ],
+        );
+        assert_eq!(
+            sdl_mapping(&device, &raw(true, &[], &[])).unwrap(),
+            format!(
+                "{GUID},Test Pad,y:b0,b:b1,a:b2,x:b3,leftshoulder:b4,rightshoulder:b5,\
+                 lefttrigger:b6,righttrigger:b7,back:b8,start:b9,leftstick:b10,rightstick:b11,\
+                 dpup:h0.1,dpdown:h0.4,dpleft:h0.8,dpright:h0.2,\
+                 leftx:a0,lefty:a1,rightx:a2,righty:a3,platform:Linux,"
+            )
+        );
+    }
+
+    #[test]
+    fn configured_bindings() {
+        let device = device(
+            &[Key::BTN_TRIGGER, Key::BTN_THUMB, Key::BTN_THUMB2],
+            &[
+                AbsoluteAxisType::ABS_X,
+                AbsoluteAxisType::ABS_Y,
+                AbsoluteAxisType::ABS_HAT0X,
+                AbsoluteAxisType::ABS_HAT0Y,
+                AbsoluteAxisType::ABS_HAT1X,
+            ],
+        );
+        // Without guessing, only what's configured is mapped.
+        let mapping = raw(
+            false,
+            &[(Button::South, "BTN_THUMB2")],
+            &[(Axis::LeftStickX, "ABS_Y")],
+        );
+        assert_eq!(
+            sdl_mapping(&device, &mapping).unwrap(),
+            format!("{GUID},Test Pad,a:b2,leftx:a1,platform:Linux,")
+        );
+        let mapping = raw(false, &[(Button::DPadLeft, "ABS_HAT0X")], &[]);
+        assert_eq!(
+            sdl_mapping(&device, &mapping).unwrap(),
+            format!("{GUID},Test Pad,dpleft:h0.8,platform:Linux,")
+        );
+
+        // Configured bindings take priority over guesses, and their buttons and codes
+        // aren't guessed again.
+        let mapping = raw(true, &[(Button::South, "BTN_TRIGGER")], &[]);
+        let bindings =
+            Bindings::new(&device, mapping.guess, &mapping.buttons, &mapping.axes).unwrap();
+        assert_eq!(
+            bindings.buttons[..3],
+            [
+                (Button::South, Binding::Key(Key::BTN_TRIGGER.code())),
+                (Button::East, Binding::Key(Key::BTN_THUMB.code())),
+                (
+                    Button::DPadUp,
+                    Binding::Hat {
+                        code: HAT0Y,
+                        positive: false
+                    }
+                ),
+            ]
+        );
+
+        for mapping in [
+            // The device doesn't have it.
+            raw(false, &[(Button::South, "BTN_SOUTH")], &[]),
+            // Up is on the hat's Y axis.
+            raw(false, &[(Button::DPadUp, "ABS_HAT0X")], &[]),
+            // gilrs only supports the first hat.
+            raw(false, &[(Button::DPadLeft, "ABS_HAT1X")], &[]),
+            // Nothing to map.
+            raw(false, &[], &[]),
+        ] {
+            assert!(sdl_mapping(&device, &mapping).is_err(), "{mapping:?}");
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The first line of every recording.
+const HEADER: &str = "# pizero-gadget-gamepads recording v1";
+
+/// Records every report written to the gadget devices, so a session can be
+/// examined or replayed later.
+///
+/// Recordings are text, one report per line, with tab-separated fields: the time in
+/// microseconds since recording started, the gadget device index, the report as hex
+/// bytes separated by spaces, and what caused the report (like the gilrs event).
+/// Lines starting with `#` are comments.
+pub struct Recorder {
+    file: LineWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Start a new recording at `path`, replacing anything already there.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {path:?}"))?;
+        let mut file = LineWriter::new(file);
+        writeln!(file, "{HEADER}")?;
+        Ok(Recorder {
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, slot: usize, report: &[u8], cause: &str) -> io::Result<()> {
+        let time = self.start.elapsed().as_micros();
+        let report = report
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        // Keep the cause on one line, whatever it is.
+        let cause = cause.replace(['\t', '\n'], " ");
+        writeln!(self.file, "{time}\t{slot}\t{report}\t{cause}")
+    }
+}
+
+/// A report read back from a recording.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedReport {
+    /// When the report was written, relative to the start of the recording.
+    pub time: Duration,
+    pub slot: usize,
+    pub report: Vec<u8>,
+    pub cause: String,
+}
+
+fn parse_line(line: &str) -> Result<RecordedReport> {
+    let mut fields = line.splitn(4, '\t');
+    let mut field = || fields.next().ok_or_else(|| anyhow!("Missing field"));
+    let time = Duration::from_micros(field()?.parse()?);
+    let slot = field()?.parse()?;
+    let report = field()?
+        .split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<_, _>>()?;
+    let cause = fields.next().unwrap_or_default().to_owned();
+    Ok(RecordedReport {
+        time,
+        slot,
+        report,
+        cause,
+    })
+}
+
+/// Read all of the reports in the recording at `path`.
+pub fn load(path: &Path) -> Result<Vec<RecordedReport>> {
+    let contents = fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| parse_line(line).with_context(|| format!("{path:?} line {}", i + 1)))
+        .collect()
+}
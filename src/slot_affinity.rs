@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Format a controller's gilrs UUID as a hex string.
+///
+/// This matches the GUID format used by SDL controller mappings.
+pub fn controller_key(uuid: [u8; 16]) -> String {
+    uuid.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// A table of which gadget device indices each known controller was last assigned to.
+///
+/// This lets a controller get the same player slot back after a reconnect or a
+/// reboot, instead of whichever gadget device happens to be free first.
+#[derive(Debug, Default)]
+pub struct SlotAffinity {
+    /// Where to persist the table, if anywhere.
+    path: Option<PathBuf>,
+    table: AffinityTable,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct AffinityTable {
+    /// Map of controller key => gadget device indices.
+    slots: BTreeMap<String, Vec<usize>>,
+}
+
+impl SlotAffinity {
+    /// Load the affinity table from `path`, or start an empty table if it doesn't exist yet.
+    ///
+    /// Changes will be saved back to `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let table = if path.exists() {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read slot affinity file: {path:?}"))?;
+            toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse slot affinity file: {path:?}"))?
+        } else {
+            AffinityTable::default()
+        };
+        Ok(SlotAffinity {
+            path: Some(path.to_owned()),
+            table,
+        })
+    }
+
+    /// The gadget device indices that the controller identified by `key` was last assigned to.
+    pub fn slots_for(&self, key: &str) -> &[usize] {
+        self.table
+            .slots
+            .get(key)
+            .map(|v| v.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// All gadget device indices that are reserved for known controllers other than `key`.
+    pub fn reserved_except(&self, key: &str) -> Vec<usize> {
+        self.table
+            .slots
+            .iter()
+            .filter(|(k, _)| *k != key)
+            .flat_map(|(_, v)| v.iter().copied())
+            .collect()
+    }
+
+    /// Record that the controller identified by `key` is using the gadget devices `slots`.
+    ///
+    /// Any other controller that had one of these slots reserved loses that reservation.
+    pub fn assign(&mut self, key: &str, slots: Vec<usize>) {
+        if self.slots_for(key) == slots.as_slice() {
+            return;
+        }
+        for v in self.table.slots.values_mut() {
+            v.retain(|i| !slots.contains(i));
+        }
+        self.table.slots.retain(|_, v| !v.is_empty());
+        debug!("Assigning slots {slots:?} to controller {key}");
+        self.table.slots.insert(key.to_owned(), slots);
+        if let Err(e) = self.save() {
+            warn!("{e:#}");
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let contents = toml::to_string(&self.table)?;
+            fs::write(path, contents)
+                .with_context(|| format!("Failed to write slot affinity file: {path:?}"))?;
+        }
+        Ok(())
+    }
+}
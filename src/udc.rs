@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use log::debug;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Where the kernel lists the USB device controllers.
+pub const UDC_CLASS_PATH: &str = "/sys/class/udc";
+/// How often to read the UDC's state.
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to wait between attempts to wake the host.
+const WAKE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state of the connection to the host, as the UDC reports it in its `state`
+/// attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UdcState {
+    NotAttached,
+    Attached,
+    Powered,
+    Reconnecting,
+    Unauthenticated,
+    Default,
+    Addressed,
+    /// The host has set up the gadget and is polling it for reports.
+    Configured,
+    /// The host has suspended the bus, usually because it's gone to sleep.
+    Suspended,
+}
+
+impl UdcState {
+    /// Parse the contents of the UDC's `state` attribute.
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s.trim() {
+            "not attached" => UdcState::NotAttached,
+            "attached" => UdcState::Attached,
+            "powered" => UdcState::Powered,
+            "reconnecting" => UdcState::Reconnecting,
+            "unauthenticated" => UdcState::Unauthenticated,
+            "default" => UdcState::Default,
+            "addressed" => UdcState::Addressed,
+            "configured" => UdcState::Configured,
+            "suspended" => UdcState::Suspended,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for UdcState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UdcState::NotAttached => "not attached",
+            UdcState::Attached => "attached",
+            UdcState::Powered => "powered",
+            UdcState::Reconnecting => "reconnecting",
+            UdcState::Unauthenticated => "unauthenticated",
+            UdcState::Default => "default",
+            UdcState::Addressed => "addressed",
+            UdcState::Configured => "configured",
+            UdcState::Suspended => "suspended",
+        })
+    }
+}
+
+/// Watches a UDC's `state` attribute for changes.
+pub struct UdcWatcher {
+    name: String,
+    state_path: PathBuf,
+    state: Option<UdcState>,
+    last_check: Option<Instant>,
+    last_wake: Option<Instant>,
+}
+
+impl UdcWatcher {
+    pub fn new(name: &str) -> Self {
+        UdcWatcher {
+            name: name.to_owned(),
+            state_path: PathBuf::from(UDC_CLASS_PATH).join(name).join("state"),
+            state: None,
+            last_check: None,
+            last_wake: None,
+        }
+    }
+
+    /// The name of the UDC, like `fe980000.usb`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The state as of the last check, or `None` if it couldn't be read.
+    pub fn state(&self) -> Option<UdcState> {
+        self.state
+    }
+
+    /// Read the state if it's time to. Returns the new state if it changed.
+    pub fn poll(&mut self) -> Option<UdcState> {
+        if self
+            .last_check
+            .is_some_and(|t| t.elapsed() < CHECK_INTERVAL)
+        {
+            return None;
+        }
+        self.last_check = Some(Instant::now());
+        let state = match fs::read_to_string(&self.state_path) {
+            Ok(contents) => UdcState::parse(&contents).or_else(|| {
+                debug!("Unknown UDC state: {:?}", contents.trim());
+                None
+            }),
+            Err(e) => {
+                debug!("Failed to read {:?}: {e}", self.state_path);
+                None
+            }
+        };
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        state
+    }
+
+    /// Ask the host to resume the gadget with remote wakeup. This only works if the
+    /// host enabled remote wakeup when it suspended the gadget.
+    ///
+    /// Returns `false` without doing anything if the last attempt was too recent.
+    pub fn wake_host(&mut self) -> Result<bool> {
+        if self.last_wake.is_some_and(|t| t.elapsed() < WAKE_INTERVAL) {
+            return Ok(false);
+        }
+        self.last_wake = Some(Instant::now());
+        // Writing 1 to `srp` makes the UDC signal remote wakeup to the host.
+        let path = PathBuf::from(UDC_CLASS_PATH).join(&self.name).join("srp");
+        fs::write(&path, "1").with_context(|| format!("Failed to write {path:?}"))?;
+        Ok(true)
+    }
+}
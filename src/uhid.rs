@@ -0,0 +1,99 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+use crate::HIDGamepad;
+
+/// The character device used to create HID devices from userspace.
+pub const UHID_PATH: &str = "/dev/uhid";
+
+/// `uhid_event` types, from `linux/uhid.h`.
+const UHID_OUTPUT: u32 = 6;
+const UHID_CREATE2: u32 = 11;
+const UHID_INPUT2: u32 = 12;
+
+/// The offsets of the `uhid_create2_req` fields within a `uhid_event`.
+const CREATE2_NAME: usize = 4;
+const CREATE2_PHYS: usize = 132;
+const CREATE2_RD_SIZE: usize = 260;
+const CREATE2_BUS: usize = 262;
+const CREATE2_VENDOR: usize = 264;
+const CREATE2_PRODUCT: usize = 268;
+const CREATE2_VERSION: usize = 272;
+const CREATE2_RD_DATA: usize = 280;
+/// The offset of the `size` field of a `uhid_output_req` within a `uhid_event`.
+const OUTPUT_SIZE: usize = 4 + DATA_MAX;
+/// The largest report uhid passes around.
+const DATA_MAX: usize = 4096;
+/// The size of a `uhid_event`, which is what reads return.
+pub const EVENT_SIZE: usize = CREATE2_RD_DATA + MAX_DESCRIPTOR_SIZE;
+/// The largest report descriptor the kernel accepts.
+const MAX_DESCRIPTOR_SIZE: usize = 4096;
+
+const BUS_USB: u16 = 0x03;
+
+/// Parse a USB ID or version number like `b"0x0f0d"`.
+pub fn parse_id(id: &[u8; 6]) -> Result<u32> {
+    let id = std::str::from_utf8(id)?;
+    let hex = id.strip_prefix("0x").unwrap_or(id);
+    u32::from_str_radix(hex, 16).with_context(|| format!("Bad USB ID: {id}"))
+}
+
+/// Copy `s` into the fixed size string field at `buf`, leaving room for the nul.
+fn write_str(buf: &mut [u8], s: &str) {
+    let len = s.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+/// Create a HID device on this machine that looks like `G`, named after `phys`, with
+/// the device release number `version`.
+///
+/// The device exists until the returned file is closed. Reports are sent to it
+/// with `input_event`.
+pub fn create_device<G: HIDGamepad>(phys: &str, version: u16) -> Result<File> {
+    if G::DESCRIPTOR.len() > MAX_DESCRIPTOR_SIZE {
+        bail!("HID descriptor is too long for uhid");
+    }
+    let mut event = vec![0; CREATE2_RD_DATA + G::DESCRIPTOR.len()];
+    event[..4].copy_from_slice(&UHID_CREATE2.to_ne_bytes());
+    let name = format!("{} {}", G::MANUFACTURER, G::PRODUCT);
+    write_str(&mut event[CREATE2_NAME..CREATE2_PHYS], &name);
+    write_str(&mut event[CREATE2_PHYS..CREATE2_PHYS + 64], phys);
+    event[CREATE2_RD_SIZE..CREATE2_BUS]
+        .copy_from_slice(&(G::DESCRIPTOR.len() as u16).to_ne_bytes());
+    event[CREATE2_BUS..CREATE2_VENDOR].copy_from_slice(&BUS_USB.to_ne_bytes());
+    event[CREATE2_VENDOR..CREATE2_PRODUCT].copy_from_slice(&parse_id(G::VENDOR_ID)?.to_ne_bytes());
+    event[CREATE2_PRODUCT..CREATE2_VERSION]
+        .copy_from_slice(&parse_id(G::PRODUCT_ID)?.to_ne_bytes());
+    event[CREATE2_VERSION..CREATE2_VERSION + 4].copy_from_slice(&(version as u32).to_ne_bytes());
+    event[CREATE2_RD_DATA..].copy_from_slice(G::DESCRIPTOR);
+
+    let mut uhid = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(UHID_PATH)
+        .with_context(|| format!("Failed to open {UHID_PATH}"))?;
+    uhid.write_all(&event)
+        .with_context(|| format!("Failed to create uhid device {phys}"))?;
+    Ok(uhid)
+}
+
+/// The output report in an event read from a uhid device, if it's an output event.
+///
+/// Everything else the kernel sends (like `UHID_START` and `UHID_OPEN`) is ignored.
+pub fn output_report(event: &[u8]) -> Option<&[u8]> {
+    if event.len() < OUTPUT_SIZE + 2 || event[..4] != UHID_OUTPUT.to_ne_bytes() {
+        return None;
+    }
+    let size = u16::from_ne_bytes([event[OUTPUT_SIZE], event[OUTPUT_SIZE + 1]]) as usize;
+    Some(&event[4..4 + size.min(DATA_MAX)])
+}
+
+/// Build the event that sends `report` from a uhid device.
+pub fn input_event(report: &[u8]) -> Vec<u8> {
+    let mut event = Vec::with_capacity(6 + report.len());
+    event.extend_from_slice(&UHID_INPUT2.to_ne_bytes());
+    event.extend_from_slice(&(report.len() as u16).to_ne_bytes());
+    event.extend_from_slice(report);
+    event
+}
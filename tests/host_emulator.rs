@@ -0,0 +1,115 @@
+use gilrs::Button;
+use pizero_gadget_gamepads::controller_state::ControllerState;
+use pizero_gadget_gamepads::hori_pokken::{HoriPokkenPad, HoriPokkenPadReport};
+use pizero_gadget_gamepads::host_emulator::{HostEmulator, Script};
+use pizero_gadget_gamepads::HIDGamepad;
+use std::path::Path;
+use std::time::Duration;
+
+/// A HORI Pokken pad that answers a handshake, like some emulated controllers need to.
+#[derive(Debug)]
+struct HandshakePad;
+
+impl HIDGamepad for HandshakePad {
+    const DESCRIPTOR: &'static [u8] = HoriPokkenPad::DESCRIPTOR;
+    const MANUFACTURER: &'static str = HoriPokkenPad::MANUFACTURER;
+    const PRODUCT: &'static str = HoriPokkenPad::PRODUCT;
+    const VENDOR_ID: &'static [u8; 6] = HoriPokkenPad::VENDOR_ID;
+    const PRODUCT_ID: &'static [u8; 6] = HoriPokkenPad::PRODUCT_ID;
+    const ANALOG_BUTTONS: bool = false;
+    type Report = HoriPokkenPadReport;
+
+    fn fill_report(state: &ControllerState) -> Self::Report {
+        HoriPokkenPad::fill_report(state)
+    }
+
+    /// Answer `80 nn` with `81 nn`, and ignore anything else.
+    fn handle_output_report(report: &[u8]) -> Vec<Vec<u8>> {
+        match report {
+            [0x80, n, ..] => vec![vec![0x81, *n]],
+            _ => vec![],
+        }
+    }
+}
+
+fn script(toml: &str) -> Script {
+    toml::from_str(toml).unwrap()
+}
+
+#[test]
+fn hori_pokken_script() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts/hori_pokken.toml");
+    let script = Script::load(&path).unwrap();
+    HostEmulator::<HoriPokkenPad>::new()
+        .unwrap()
+        .run(&script)
+        .unwrap();
+}
+
+#[test]
+fn output_report_round_trip() {
+    let mut host = HostEmulator::<HandshakePad>::new().unwrap();
+    host.send(&[0x80, 0x02]).unwrap();
+    let reply = host.receive(Duration::from_millis(100)).unwrap();
+    assert_eq!(reply, Some(vec![0x81, 0x02]));
+    // Output reports that the pad doesn't answer don't get a reply.
+    host.send(&[0x01]).unwrap();
+    assert_eq!(host.receive(Duration::from_millis(10)).unwrap(), None);
+
+    let mut state = ControllerState::default();
+    state.press(&[Button::East]);
+    host.write_state(&state).unwrap();
+    let report = host.receive(Duration::from_millis(100)).unwrap();
+    assert_eq!(report, Some(vec![0x04, 0, 0x08, 0x80, 0x80, 0x80, 0x80, 0]));
+}
+
+#[test]
+fn handshake_script() {
+    let script = script(
+        r#"
+        [[step]]
+        send = "80 02"
+
+        [[step]]
+        expect = "81 02"
+
+        [[step]]
+        press = ["South"]
+
+        [[step]]
+        expect = "02 00 08 80 80 80 80 00"
+        "#,
+    );
+    HostEmulator::<HandshakePad>::new()
+        .unwrap()
+        .run(&script)
+        .unwrap();
+}
+
+#[test]
+fn failing_scripts() {
+    // A report that doesn't match.
+    let wrong = script(
+        r#"
+        [[step]]
+        press = ["South"]
+
+        [[step]]
+        expect = "04 00 08 80 80 80 80 00"
+        "#,
+    );
+    let mut host = HostEmulator::<HoriPokkenPad>::new().unwrap();
+    let err = host.run(&wrong).unwrap_err();
+    assert!(format!("{err:#}").contains("Step 2 failed"), "{err:#}");
+
+    // A reply that the script doesn't expect.
+    let unexpected = script(
+        r#"
+        [[step]]
+        send = "80 01"
+        "#,
+    );
+    let mut host = HostEmulator::<HandshakePad>::new().unwrap();
+    let err = host.run(&unexpected).unwrap_err();
+    assert!(format!("{err:#}").contains("Unexpected report"), "{err:#}");
+}